@@ -3,10 +3,99 @@
 //! This module provides optimized implementations of CPU-intensive operations
 //! used in the browser's AI response processing and HTML rendering.
 
-use pyo3::prelude::*;
+use base64::engine::general_purpose;
+use base64::Engine;
 use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use rayon::prelude::*;
 use regex::Regex;
-use std::collections::HashSet;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::LazyLock;
+use unicode_width::UnicodeWidthStr;
+use url::Url;
+
+static BOLD_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\*\*(.*?)\*\*").unwrap());
+static ITALIC_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\*(.*?)\*").unwrap());
+static STRIKE_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"~~(.+?)~~").unwrap());
+static SUBSCRIPT_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"~([^~\s/]+)~").unwrap());
+static SUPERSCRIPT_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\^([^\^\s/]+)\^").unwrap());
+static TAG_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<[^>]*>").unwrap());
+static ATTR_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"([^\s"'<>/=]+)(?:\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'=<>`]+)))?"#).unwrap()
+});
+static NUMBER_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"([-+]?)((?:\d{1,3}(?:,\d{3})+(?:\.\d+)?|\d+(?:\.\d+)?|\.\d+)(?:[eE][-+]?\d+)?)(%|[a-zA-Z]+)?")
+        .unwrap()
+});
+static HEADING_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?is)<h([1-6])(\s[^>]*)?>(.*?)</h([1-6])\s*>").unwrap());
+static PLACEHOLDER_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{\{\s*([\w.-]+)\s*\}\}").unwrap());
+static LINK_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(!?)\[([^\]]*)\]\(\s*([^)\s]*)(?:\s+"[^"]*")?\s*\)"#).unwrap());
+static LIST_ITEM_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\s*(?:[-*+]|\d+[.)])\s+(.*)$").unwrap());
+static EMPHASIS_MARKERS_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\*\*(.+?)\*\*|__(.+?)__|~~(.+?)~~|\*(.+?)\*|\b_(.+?)_\b|`([^`]+)`").unwrap()
+});
+const VOID_ELEMENTS: [&str; 14] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+static TASK_ITEM_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\s*[-*+]\s+\[([ xX])\]\s+(.*)$").unwrap());
+static DEFINITION_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^ {0,3}:\s+(.*?)\s*$").unwrap());
+const TRACKING_PARAMS: [&str; 10] = [
+    "fbclid", "gclid", "dclid", "gbraid", "wbraid", "msclkid", "yclid", "igshid", "mc_cid",
+    "mc_eid",
+];
+const NAMED_COLORS: [(&str, &str); 17] = [
+    ("aqua", "#00ffff"),
+    ("black", "#000000"),
+    ("blue", "#0000ff"),
+    ("fuchsia", "#ff00ff"),
+    ("gray", "#808080"),
+    ("green", "#008000"),
+    ("lime", "#00ff00"),
+    ("maroon", "#800000"),
+    ("navy", "#000080"),
+    ("olive", "#808000"),
+    ("orange", "#ffa500"),
+    ("purple", "#800080"),
+    ("red", "#ff0000"),
+    ("silver", "#c0c0c0"),
+    ("teal", "#008080"),
+    ("white", "#ffffff"),
+    ("yellow", "#ffff00"),
+];
+static CSS_IMPORT_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?i)@import\s+(?:url\(\s*)?["']?([^"')\s;]+)"#).unwrap());
+static BARE_URL_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"https?://[^\s<>()\[\]]+").unwrap());
+static CHAR_REF_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"&(?:#[xX][0-9a-fA-F]+|#[0-9]+|[A-Za-z][A-Za-z0-9]*);").unwrap());
+static EMAIL_CANDIDATE_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"[A-Za-z0-9.!#$%&'*+/=?^_`{|}~-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)+").unwrap()
+});
+static PHONE_CANDIDATE_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\+?\(?\d[\d \t().-]{5,}\d").unwrap());
+static CSS_COMMENT_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)/\*.*?(?:\*/|$)").unwrap());
+static CSS_RULE_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"([^{}]*)\{([^{}]*)\}").unwrap());
+static ESCAPED_PUNCT_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\\([!-/:-@\[-`{-~])").unwrap());
+static ESCAPED_DOLLAR_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\\[\\$]").unwrap());
+static BLOCK_MATH_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?s)\$\$(.+?)\$\$").unwrap());
+static INLINE_MATH_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\$([^\s$](?:[^$\n]*[^\s$])?)\$").unwrap());
+static MATH_PLACEHOLDER_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new("\u{E000}(\\d+)\u{E001}").unwrap());
 
 /// Extract a URL from text using a regex pattern
 ///
@@ -21,11 +110,11 @@ use std::collections::HashSet;
 /// The first captured group if a match is found, None otherwise
 #[pyfunction]
 fn extract_url_from_text(text: &str, pattern: &str) -> PyResult<Option<String>> {
-    let re = Regex::new(pattern).map_err(|e| {
-        PyValueError::new_err(format!("Invalid regex pattern: {}", e))
-    })?;
+    let re = Regex::new(pattern)
+        .map_err(|e| PyValueError::new_err(format!("Invalid regex pattern: {}", e)))?;
 
-    Ok(re.captures(&text.to_lowercase())
+    Ok(re
+        .captures(&text.to_lowercase())
         .and_then(|cap| cap.get(1))
         .map(|m| m.as_str().to_string()))
 }
@@ -75,6 +164,45 @@ fn fast_string_contains(text: &str, keywords: HashSet<String>) -> bool {
     keywords.iter().any(|keyword| text_lower.contains(keyword))
 }
 
+/// Compare which keywords two texts mention (case-insensitive)
+///
+/// # Arguments
+/// * `text_a` - First text
+/// * `text_b` - Second text
+/// * `keywords` - Set of keywords to look for
+///
+/// # Returns
+/// Tuple of (only_in_a, only_in_b, in_both) keyword lists, each sorted;
+/// keywords found in neither text are omitted
+#[pyfunction]
+fn keyword_diff(
+    text_a: &str,
+    text_b: &str,
+    keywords: HashSet<String>,
+) -> (Vec<String>, Vec<String>, Vec<String>) {
+    let a_lower = text_a.to_lowercase();
+    let b_lower = text_b.to_lowercase();
+    let (mut only_a, mut only_b, mut both) = (Vec::new(), Vec::new(), Vec::new());
+
+    for keyword in keywords {
+        let keyword_lower = keyword.to_lowercase();
+        match (
+            a_lower.contains(&keyword_lower),
+            b_lower.contains(&keyword_lower),
+        ) {
+            (true, true) => both.push(keyword),
+            (true, false) => only_a.push(keyword),
+            (false, true) => only_b.push(keyword),
+            (false, false) => {}
+        }
+    }
+
+    only_a.sort_unstable();
+    only_b.sort_unstable();
+    both.sort_unstable();
+    (only_a, only_b, both)
+}
+
 /// Encode bytes to base64 string
 ///
 /// Optimized base64 encoding, typically 3-10x faster than Python's
@@ -87,77 +215,6529 @@ fn fast_string_contains(text: &str, keywords: HashSet<String>) -> bool {
 /// Base64 encoded string
 #[pyfunction]
 fn base64_encode_optimized(data: &[u8]) -> String {
-    base64::encode(data)
+    general_purpose::STANDARD.encode(data)
+}
+
+/// Convert runs of `- [ ]` / `- [x]` items into HTML task lists
+///
+/// Each run becomes a `<ul class="task-list">` with a disabled checkbox
+/// per item; other lines are returned unchanged.
+fn convert_task_lists(text: &str) -> String {
+    let mut lines = Vec::new();
+    let mut in_list = false;
+
+    for line in text.split('\n') {
+        match TASK_ITEM_RE.captures(line) {
+            Some(cap) => {
+                if !in_list {
+                    lines.push("<ul class=\"task-list\">".to_string());
+                    in_list = true;
+                }
+                let checked = if cap[1].eq_ignore_ascii_case("x") {
+                    " checked"
+                } else {
+                    ""
+                };
+                lines.push(format!(
+                    "<li><input type=\"checkbox\" disabled{}>{}</li>",
+                    checked, &cap[2]
+                ));
+            }
+            None => {
+                if in_list {
+                    lines.push("</ul>".to_string());
+                    in_list = false;
+                }
+                lines.push(line.to_string());
+            }
+        }
+    }
+    if in_list {
+        lines.push("</ul>".to_string());
+    }
+
+    lines.join("\n")
+}
+
+/// Check whether a line opens with a block-level HTML tag such as `<hr>`
+fn starts_with_block_tag(line: &str) -> bool {
+    const BLOCK_TAGS: [&str; 20] = [
+        "blockquote",
+        "dd",
+        "div",
+        "dl",
+        "dt",
+        "h1",
+        "h2",
+        "h3",
+        "h4",
+        "h5",
+        "h6",
+        "hr",
+        "li",
+        "ol",
+        "p",
+        "pre",
+        "table",
+        "td",
+        "tr",
+        "ul",
+    ];
+    match HtmlTokenizer::new(line.trim_start()).next() {
+        Some(HtmlToken::StartTag { name, .. } | HtmlToken::EndTag { name, .. }) => {
+            BLOCK_TAGS.contains(&name.as_str())
+        }
+        _ => false,
+    }
+}
+
+/// Convert `Term` / `: Definition` runs into HTML definition lists
+///
+/// A term is a non-blank line directly followed by one or more `: `
+/// definition lines; consecutive pairs share one `<dl>`, and any other
+/// line ends the list. Lines already turned into block HTML by earlier
+/// passes (`<hr>`, task list items) are never used as terms.
+fn convert_definition_lists(text: &str) -> String {
+    let lines: Vec<&str> = text.split('\n').collect();
+    let mut output = Vec::with_capacity(lines.len());
+    let mut in_list = false;
+
+    for (i, line) in lines.iter().enumerate() {
+        if let Some(cap) = DEFINITION_RE.captures(line).filter(|_| in_list) {
+            output.push(format!("<dd>{}</dd>", &cap[1]));
+            continue;
+        }
+        let is_term = !line.trim().is_empty()
+            && !DEFINITION_RE.is_match(line)
+            && !starts_with_block_tag(line)
+            && lines
+                .get(i + 1)
+                .is_some_and(|next| DEFINITION_RE.is_match(next));
+        if is_term {
+            if !in_list {
+                output.push("<dl>".to_string());
+                in_list = true;
+            }
+            output.push(format!("<dt>{}</dt>", line.trim()));
+        } else {
+            if in_list {
+                output.push("</dl>".to_string());
+                in_list = false;
+            }
+            output.push(line.to_string());
+        }
+    }
+    if in_list {
+        output.push("</dl>".to_string());
+    }
+
+    output.join("\n")
+}
+
+/// Check whether a line is a thematic break such as `---` or `* * *`
+///
+/// The line must consist solely of three or more of the same `-`, `*`
+/// or `_` character, optionally separated by spaces, and as in CommonMark
+/// be indented by at most three spaces.
+fn is_horizontal_rule(line: &str) -> bool {
+    let unindented = line.trim_start_matches(' ');
+    if line.len() - unindented.len() > 3 || unindented.starts_with('\t') {
+        return false;
+    }
+    let mut markers = unindented.chars().filter(|c| !c.is_whitespace());
+    let Some(marker) = markers.next().filter(|c| matches!(c, '-' | '*' | '_')) else {
+        return false;
+    };
+    let mut count = 1;
+    for c in markers {
+        if c != marker {
+            return false;
+        }
+        count += 1;
+    }
+    count >= 3
+}
+
+/// Count the lines of a leading YAML frontmatter block, if any
+///
+/// The block opens with `---` on the first line and closes with the
+/// next `---` or `...` line.
+fn frontmatter_line_count(text: &str) -> usize {
+    let mut lines = text.split('\n');
+    if lines.next().map(str::trim_end) != Some("---") {
+        return 0;
+    }
+    lines
+        .position(|line| matches!(line.trim_end(), "---" | "..."))
+        .map_or(0, |closing| closing + 2)
+}
+
+/// Replace thematic break lines with `<hr>`
+///
+/// A leading frontmatter block is left untouched, so its `---`
+/// delimiters aren't mistaken for rules.
+fn convert_horizontal_rules(text: &str) -> String {
+    let frontmatter = frontmatter_line_count(text);
+    text.split('\n')
+        .enumerate()
+        .map(|(i, line)| {
+            if i >= frontmatter && is_horizontal_rule(line) {
+                "<hr>"
+            } else {
+                line
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Move LaTeX math spans out of the way of the other markdown passes
+///
+/// `$$...$$` becomes a `<div class="math block">` and `$...$` a
+/// `<span class="math inline">`, with the LaTeX left untouched for a
+/// downstream renderer. Each span is replaced by a placeholder that
+/// `restore_math` swaps back once the other passes have run.
+/// Following Pandoc, an inline span can't start or end with a space and
+/// its closing `$` can't be followed by a digit, so `$5` stays literal.
+/// An escaped `\$` never delimits math: it is kept as `\$` inside a span
+/// and becomes a literal `$` elsewhere. The private-use characters used
+/// as markers are turned into character references first, so input can't
+/// forge a placeholder.
+fn extract_math(text: &str, snippets: &mut Vec<String>) -> String {
+    let text = text
+        .replace('\u{E000}', "&#57344;")
+        .replace('\u{E001}', "&#57345;")
+        .replace('\u{E002}', "&#57346;");
+    let mut placeholder = |html: String| {
+        snippets.push(html.replace('\u{E002}', "\\$"));
+        format!("\u{E000}{}\u{E001}", snippets.len() - 1)
+    };
+
+    // `\\` is matched too so the `$` in `\\$` still counts as a delimiter
+    let text = ESCAPED_DOLLAR_RE.replace_all(&text, |cap: &regex::Captures| {
+        if &cap[0] == "\\$" {
+            "\u{E002}".to_string()
+        } else {
+            cap[0].to_string()
+        }
+    });
+    let text = BLOCK_MATH_RE.replace_all(&text, |cap: &regex::Captures| {
+        placeholder(format!("<div class=\"math block\">{}</div>", cap[1].trim()))
+    });
+
+    let mut result = String::with_capacity(text.len());
+    let mut last = 0;
+    for cap in INLINE_MATH_RE.captures_iter(&text) {
+        let whole = cap.get(0).unwrap();
+        if text[whole.end()..].starts_with(|c: char| c.is_ascii_digit()) {
+            continue;
+        }
+        result.push_str(&text[last..whole.start()]);
+        result.push_str(&placeholder(format!(
+            "<span class=\"math inline\">{}</span>",
+            &cap[1]
+        )));
+        last = whole.end();
+    }
+    result.push_str(&text[last..]);
+    result
+}
+
+/// Replace the placeholders left by `extract_math` with their HTML
+fn restore_math(text: &str, snippets: &[String]) -> String {
+    MATH_PLACEHOLDER_RE
+        .replace_all(
+            &text.replace('\u{E002}', "&#36;"),
+            |cap: &regex::Captures| {
+                cap[1]
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|index| snippets.get(index))
+                    .cloned()
+                    .unwrap_or_else(|| cap[0].to_string())
+            },
+        )
+        .into_owned()
+}
+
+/// Wrap bare email addresses in `mailto:` links
+///
+/// Only text outside tags and existing `<a>` elements is touched, and
+/// addresses that are part of a URL (`mailto:x@y.com`, `https://user@host`)
+/// or fail `is_valid_email` are left alone.
+fn autolink_email_addresses(html: &str) -> String {
+    let mut result = String::with_capacity(html.len());
+    let mut link_depth = 0usize;
+
+    for token in HtmlTokenizer::new(html) {
+        match token {
+            HtmlToken::StartTag {
+                ref name,
+                self_closing: false,
+                ..
+            } if name == "a" => link_depth += 1,
+            HtmlToken::EndTag { ref name, .. } if name == "a" => {
+                link_depth = link_depth.saturating_sub(1)
+            }
+            HtmlToken::Text(text) if link_depth == 0 => {
+                let mut last = 0;
+                for m in EMAIL_CANDIDATE_RE.find_iter(text) {
+                    let in_url = text[..m.start()].ends_with([':', '/']);
+                    if in_url || !is_valid_email(m.as_str()) {
+                        continue;
+                    }
+                    result.push_str(&text[last..m.start()]);
+                    result.push_str(&format!(
+                        "<a href=\"mailto:{}\">{}</a>",
+                        escape_html_text(m.as_str()),
+                        m.as_str()
+                    ));
+                    last = m.end();
+                }
+                result.push_str(&text[last..]);
+                continue;
+            }
+            _ => {}
+        }
+        result.push_str(token.raw());
+    }
+
+    result
+}
+
+/// Replace `~sub~` and `^sup^` spans with `<sub>` and `<sup>` tags
+///
+/// URLs are left alone, and so are markers right after a `/`, so paths
+/// like `~/dir` or `http://host/~user` keep their tildes.
+fn convert_sub_superscripts(text: &str) -> String {
+    fn convert(segment: &str, re: &Regex, tag: &str) -> String {
+        re.replace_all(segment, |cap: &regex::Captures| {
+            let start = cap.get(0).unwrap().start();
+            if segment[..start].ends_with('/') {
+                cap[0].to_string()
+            } else {
+                format!("<{tag}>{}</{tag}>", &cap[1])
+            }
+        })
+        .into_owned()
+    }
+
+    let mut result = String::with_capacity(text.len());
+    let mut last = 0;
+    let urls = BARE_URL_RE
+        .find_iter(text)
+        .map(|m| (m.start(), m.end()))
+        .chain(std::iter::once((text.len(), text.len())));
+    for (start, end) in urls {
+        let segment = convert(&text[last..start], &SUBSCRIPT_RE, "sub");
+        result.push_str(&convert(&segment, &SUPERSCRIPT_RE, "sup"));
+        result.push_str(&text[start..end]);
+        last = end;
+    }
+    result
 }
 
 /// Convert simple markdown formatting to HTML
 ///
 /// Optimized conversion of **bold** and *italic* markers,
 /// typically 2-4x faster than Python regex substitutions.
+/// `~~strike~~`, `H~2~O` and `x^2^` become `<del>`, `<sub>` and `<sup>`
+/// (sub- and superscripts can't contain spaces).
+/// Task list items (`- [ ]` and `- [x]`) become disabled checkboxes,
+/// `Term` / `: Definition` lines become definition lists and lines like
+/// `---` or `* * *` become `<hr>`. Backslash-escaped punctuation (`\*`)
+/// is output as a literal character, and `$...$` / `$$...$$` LaTeX is
+/// wrapped for a math renderer without emphasis conversion inside. With
+/// `autolink_emails`, bare email addresses become `mailto:` links.
 ///
 /// # Arguments
 /// * `text` - Text with markdown formatting
+/// * `autolink_emails` - Link bare email addresses
 ///
 /// # Returns
 /// HTML formatted text
 #[pyfunction]
-fn markdown_to_html(text: &str) -> String {
+#[pyo3(signature = (text, autolink_emails=false))]
+fn markdown_to_html(text: &str, autolink_emails: bool) -> String {
+    let mut snippets = Vec::new();
+    let text = extract_math(text, &mut snippets);
+
+    // Turn \* and friends into character references so no marker sees them
+    let text = ESCAPED_PUNCT_RE.replace_all(&text, |cap: &regex::Captures| {
+        format!("&#{};", cap[1].chars().next().unwrap() as u32)
+    });
+    let text = convert_horizontal_rules(&text);
+    let text = convert_task_lists(&text);
+    let text = convert_definition_lists(&text);
+
     // Replace **bold** with <strong>bold</strong>
-    let bold_re = Regex::new(r"\*\*(.*?)\*\*").unwrap();
-    let text = bold_re.replace_all(text, "<strong>$1</strong>");
+    let text = BOLD_RE.replace_all(&text, "<strong>$1</strong>");
 
     // Replace *italic* with <em>italic</em>
-    let italic_re = Regex::new(r"\*(.*?)\*").unwrap();
-    let text = italic_re.replace_all(&text, "<em>$1</em>");
+    let text = ITALIC_RE.replace_all(&text, "<em>$1</em>");
+
+    // Strikethrough goes first so `~~` isn't read as two subscripts
+    let text = STRIKE_RE.replace_all(&text, "<del>$1</del>");
+    let text = convert_sub_superscripts(&text);
 
-    text.to_string()
+    let text = if autolink_emails {
+        autolink_email_addresses(&text)
+    } else {
+        text
+    };
+
+    restore_math(&text, &snippets)
 }
 
-/// Python module definition
-#[pymodule]
-fn minimal_browser_native(_py: Python, m: &PyModule) -> PyResult<()> {
-    m.add_function(wrap_pyfunction!(extract_url_from_text, m)?)?;
-    m.add_function(wrap_pyfunction!(find_all_patterns, m)?)?;
-    m.add_function(wrap_pyfunction!(fast_string_contains, m)?)?;
-    m.add_function(wrap_pyfunction!(base64_encode_optimized, m)?)?;
-    m.add_function(wrap_pyfunction!(markdown_to_html, m)?)?;
-    Ok(())
+/// Convert a batch of markdown texts to HTML in a single call
+///
+/// Avoids crossing the FFI boundary once per message when rendering a
+/// whole conversation. The GIL is released while the batch is rendered
+/// in parallel.
+///
+/// # Arguments
+/// * `texts` - Texts with markdown formatting
+/// * `autolink_emails` - Link bare email addresses
+///
+/// # Returns
+/// HTML formatted texts, in input order
+#[pyfunction]
+#[pyo3(signature = (texts, autolink_emails=false))]
+fn markdown_to_html_batch(py: Python<'_>, texts: Vec<&str>, autolink_emails: bool) -> Vec<String> {
+    py.allow_threads(|| {
+        texts
+            .par_iter()
+            .map(|text| markdown_to_html(text, autolink_emails))
+            .collect()
+    })
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// A URL split for segment-wise prefix comparison
+struct UrlSegments {
+    /// Parsed URL; scheme-less input is parsed as `http://`
+    url: Url,
+    has_scheme: bool,
+    /// Non-empty path segments as they appear in the URL
+    segments: Vec<String>,
+}
 
-    #[test]
-    fn test_extract_url() {
-        let text = "navigate to example.com for more info";
-        let pattern = r"(?:navigate|go|open)\s+(?:to\s+)?([^\s]+\.[a-z]{2,})";
-        let result = extract_url_from_text(text, pattern).unwrap();
-        assert_eq!(result, Some("example.com".to_string()));
+impl UrlSegments {
+    fn parse(input: &str) -> Option<Self> {
+        let (url, has_scheme) = match Url::parse(input) {
+            Ok(url) => (url, true),
+            Err(url::ParseError::RelativeUrlWithoutBase) => {
+                (Url::parse(&format!("http://{}", input)).ok()?, false)
+            }
+            Err(_) => return None,
+        };
+        url.host_str()?;
+        let segments = url
+            .path_segments()
+            .into_iter()
+            .flatten()
+            .filter(|segment| !segment.is_empty())
+            .map(str::to_string)
+            .collect();
+        Some(UrlSegments {
+            url,
+            has_scheme,
+            segments,
+        })
     }
 
-    #[test]
-    fn test_fast_string_contains() {
-        let text = "create a todo list";
-        let keywords: HashSet<String> = ["create", "make", "generate"]
+    /// Whether both URLs point at the same origin and user
+    fn same_origin(&self, other: &UrlSegments) -> bool {
+        self.has_scheme == other.has_scheme
+            && self.url.scheme() == other.url.scheme()
+            && self.url.host_str() == other.url.host_str()
+            && self.url.port_or_known_default() == other.url.port_or_known_default()
+            && self.url.username() == other.url.username()
+            && self.url.password() == other.url.password()
+    }
+}
+
+/// Compare path segments with unreserved percent-escapes decoded
+fn same_segment(a: &str, b: &str) -> bool {
+    a == b || percent_decode_for_display(a, b"/%") == percent_decode_for_display(b, b"/%")
+}
+
+/// Compute the longest common prefix shared by a list of URLs
+///
+/// Prefixes are compared per path segment rather than per character,
+/// so `example.com/a/bc` and `example.com/a/bd` share `example.com/a`.
+/// Hosts are compared case-insensitively and default ports are ignored,
+/// so `https://A.com:443/x` and `https://a.com/x` share `https://a.com/x`.
+///
+/// # Arguments
+/// * `urls` - URLs to compare
+///
+/// # Returns
+/// The shared scheme, host and path segments, or an empty string when
+/// the URLs do not share a scheme and host or can't be parsed
+#[pyfunction]
+fn common_url_prefix(urls: Vec<String>) -> String {
+    let Some(parsed) = urls
+        .iter()
+        .map(|url| UrlSegments::parse(url))
+        .collect::<Option<Vec<_>>>()
+    else {
+        return String::new();
+    };
+    let Some((first, rest)) = parsed.split_first() else {
+        return String::new();
+    };
+
+    let mut shared = first.segments.len();
+    for other in rest {
+        if !first.same_origin(other) {
+            return String::new();
+        }
+        shared = first.segments[..shared]
             .iter()
-            .map(|s| s.to_string())
-            .collect();
-        assert!(fast_string_contains(text, keywords));
+            .zip(&other.segments)
+            .take_while(|(a, b)| same_segment(a, b))
+            .count();
     }
 
-    #[test]
-    fn test_markdown_to_html() {
-        let text = "This is **bold** and *italic* text";
-        let result = markdown_to_html(text);
-        assert!(result.contains("<strong>bold</strong>"));
-        assert!(result.contains("<em>italic</em>"));
+    let url = &first.url;
+    let mut prefix = String::new();
+    if first.has_scheme {
+        prefix.push_str(url.scheme());
+        prefix.push_str("://");
+    }
+    if !url.username().is_empty() {
+        prefix.push_str(url.username());
+        if let Some(password) = url.password() {
+            prefix.push(':');
+            prefix.push_str(password);
+        }
+        prefix.push('@');
+    }
+    prefix.push_str(url.host_str().unwrap_or(""));
+    if let Some(port) = url.port() {
+        prefix.push_str(&format!(":{}", port));
     }
+    for segment in &first.segments[..shared] {
+        prefix.push('/');
+        prefix.push_str(segment);
+    }
+    prefix
+}
 
-    #[test]
-    fn test_base64_encode() {
-        let data = b"Hello, World!";
-        let result = base64_encode_optimized(data);
-        assert_eq!(result, "SGVsbG8sIFdvcmxkIQ==");
+/// Decode HTML character references
+///
+/// Handles the full HTML5 set of named references as well as decimal
+/// (`&#169;`) and hex (`&#x1F600;`) numeric ones. References must end
+/// with `;`; unknown names and invalid code points are left as-is.
+///
+/// # Arguments
+/// * `text` - Text containing character references
+///
+/// # Returns
+/// The decoded text
+#[pyfunction]
+fn decode_html_entities(text: &str) -> String {
+    html_escape::decode_html_entities(text).into_owned()
+}
+
+/// Convert heading text into a URL-friendly anchor slug
+fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if (c.is_whitespace() || c == '-' || c == '_') && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_matches('-');
+    if slug.is_empty() {
+        "section".to_string()
+    } else {
+        slug.to_string()
+    }
+}
+
+/// Return `slug`, suffixed with `-1`, `-2`, ... if it is already taken
+fn unique_slug(slug: String, used: &mut HashSet<String>) -> String {
+    let mut candidate = slug.clone();
+    let mut suffix = 1;
+    while used.contains(&candidate) {
+        candidate = format!("{}-{}", slug, suffix);
+        suffix += 1;
+    }
+    used.insert(candidate.clone());
+    candidate
+}
+
+/// Parse the attributes of an HTML start tag
+///
+/// Attribute names are lowercased and values have entities decoded.
+/// Attributes without a value map to an empty string.
+fn parse_attributes(attrs: &str) -> Vec<(String, String)> {
+    ATTR_RE
+        .captures_iter(attrs)
+        .map(|cap| {
+            let value = cap
+                .get(2)
+                .or_else(|| cap.get(3))
+                .or_else(|| cap.get(4))
+                .map_or("", |m| m.as_str());
+            (cap[1].to_lowercase(), decode_html_entities(value))
+        })
+        .collect()
+}
+
+/// Look up an attribute value by (lowercase) name
+fn find_attribute<'a>(attrs: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    attrs
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str())
+}
+
+/// A heading found in rendered HTML
+struct Heading {
+    level: u8,
+    text: String,
+    anchor: String,
+    /// Whether `anchor` comes from an `id` attribute already on the tag
+    has_id: bool,
+    /// Byte offset just before the `>` closing the start tag
+    open_tag_end: usize,
+}
+
+/// Find every `<h1>`-`<h6>` in `html` and assign each a unique anchor
+///
+/// Headings that already carry an `id` keep it; the others get a
+/// slug of their text, suffixed with `-1`, `-2`, ... on duplicates.
+fn scan_headings(html: &str) -> Vec<Heading> {
+    let matches: Vec<_> = HEADING_RE
+        .captures_iter(html)
+        .filter(|cap| cap[1] == cap[4])
+        .map(|cap| {
+            let attrs = parse_attributes(cap.get(2).map_or("", |m| m.as_str()));
+            let existing_id = find_attribute(&attrs, "id")
+                .filter(|id| !id.is_empty())
+                .map(str::to_string);
+            (cap, existing_id)
+        })
+        .collect();
+
+    // Reserve every existing id up front so generated slugs never collide
+    // with an id that appears later in the document
+    let mut used: HashSet<String> = matches.iter().filter_map(|(_, id)| id.clone()).collect();
+    let mut headings = Vec::new();
+
+    for (cap, existing_id) in matches {
+        let level = cap[1].parse().unwrap_or(1);
+        let text = TAG_RE.replace_all(&cap[3], "");
+        let text = decode_html_entities(text.trim());
+        let has_id = existing_id.is_some();
+        let anchor = match existing_id {
+            Some(id) => id,
+            None => unique_slug(slugify(&text), &mut used),
+        };
+        // The heading content starts right after the `>` of the start tag
+        let open_tag_end = cap.get(3).unwrap().start() - 1;
+        headings.push(Heading {
+            level,
+            text,
+            anchor,
+            has_id,
+            open_tag_end,
+        });
+    }
+
+    headings
+}
+
+/// Extract a table of contents from HTML headings
+///
+/// # Arguments
+/// * `html` - Rendered HTML
+///
+/// # Returns
+/// List of (level, text, anchor_id) tuples for each `<h1>`-`<h6>`, in
+/// document order, with duplicate headings given unique anchor ids
+#[pyfunction]
+fn extract_toc(html: &str) -> Vec<(u8, String, String)> {
+    scan_headings(html)
+        .into_iter()
+        .map(|heading| (heading.level, heading.text, heading.anchor))
+        .collect()
+}
+
+/// Add anchor ids to HTML headings
+///
+/// Uses the same anchors as `extract_toc`, so links generated from the
+/// table of contents resolve. Existing attributes are preserved and
+/// headings that already have an `id` are left untouched.
+///
+/// # Arguments
+/// * `html` - Rendered HTML
+///
+/// # Returns
+/// HTML with an `id` attribute on every heading
+#[pyfunction]
+fn add_heading_anchors(html: &str) -> String {
+    let mut result = String::with_capacity(html.len());
+    let mut last = 0;
+
+    for heading in scan_headings(html).into_iter().filter(|h| !h.has_id) {
+        let insert_at = html[..heading.open_tag_end].trim_end().len();
+        result.push_str(&html[last..insert_at]);
+        result.push_str(&format!(" id=\"{}\"", heading.anchor));
+        last = insert_at;
+    }
+
+    result.push_str(&html[last..]);
+    result
+}
+
+/// A token produced by `HtmlTokenizer`
+///
+/// Tokens borrow from the input and, concatenated in order, reproduce
+/// it exactly.
+#[derive(Debug, PartialEq)]
+enum HtmlToken<'a> {
+    Text(&'a str),
+    StartTag {
+        raw: &'a str,
+        name: String,
+        attrs: &'a str,
+        self_closing: bool,
+    },
+    EndTag {
+        raw: &'a str,
+        name: String,
+    },
+    /// Comments, doctypes and other `<!...>` / `<?...>` markup
+    Other(&'a str),
+}
+
+impl<'a> HtmlToken<'a> {
+    fn raw(&self) -> &'a str {
+        match self {
+            HtmlToken::Text(raw) | HtmlToken::Other(raw) => raw,
+            HtmlToken::StartTag { raw, .. } | HtmlToken::EndTag { raw, .. } => raw,
+        }
+    }
+}
+
+/// Streaming HTML tokenizer
+///
+/// Splits HTML into text, tags and comments without building a tree.
+/// The contents of `<script>` and `<style>` are returned as text, and
+/// stray `<` characters that don't start a tag are treated as text.
+struct HtmlTokenizer<'a> {
+    html: &'a str,
+    pos: usize,
+    /// Set after a `<script>`/`<style>` start tag, whose contents are raw text
+    raw_text_end: Option<String>,
+}
+
+impl<'a> HtmlTokenizer<'a> {
+    fn new(html: &'a str) -> Self {
+        HtmlTokenizer {
+            html,
+            pos: 0,
+            raw_text_end: None,
+        }
+    }
+
+    /// Find the `>` ending a tag starting at `start`, skipping quoted values
+    fn find_tag_end(&self, start: usize) -> Option<usize> {
+        let mut quote = None;
+        for (i, c) in self.html[start..].char_indices() {
+            match (quote, c) {
+                (None, '"' | '\'') => quote = Some(c),
+                (Some(q), _) if q == c => quote = None,
+                (None, '>') => return Some(start + i),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Return the token spanning `start..end` and advance past it
+    fn advance(&mut self, end: usize) -> &'a str {
+        let raw = &self.html[self.pos..end];
+        self.pos = end;
+        raw
+    }
+}
+
+impl<'a> Iterator for HtmlTokenizer<'a> {
+    type Item = HtmlToken<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = &self.html[self.pos..];
+        if rest.is_empty() {
+            return None;
+        }
+
+        if let Some(name) = self.raw_text_end.take() {
+            let closing = format!("</{}", name);
+            let end = rest
+                .to_ascii_lowercase()
+                .find(&closing)
+                .map_or(self.html.len(), |i| self.pos + i);
+            if end > self.pos {
+                return Some(HtmlToken::Text(self.advance(end)));
+            }
+        }
+
+        if rest.starts_with("<!--") {
+            let end = rest
+                .find("-->")
+                .map_or(self.html.len(), |i| self.pos + i + 3);
+            return Some(HtmlToken::Other(self.advance(end)));
+        }
+
+        let bytes = rest.as_bytes();
+        let starts_tag = bytes[0] == b'<'
+            && bytes
+                .get(1)
+                .is_some_and(|b| b.is_ascii_alphabetic() || matches!(b, b'/' | b'!' | b'?'));
+        if starts_tag {
+            if let Some(end) = self.find_tag_end(self.pos + 1) {
+                let raw = self.advance(end + 1);
+                let inner = &raw[1..raw.len() - 1];
+                if inner.starts_with('!') || inner.starts_with('?') {
+                    return Some(HtmlToken::Other(raw));
+                }
+                if let Some(inner) = inner.strip_prefix('/') {
+                    let name = inner.trim().to_ascii_lowercase();
+                    return Some(HtmlToken::EndTag { raw, name });
+                }
+                let name_len = inner
+                    .find(|c: char| c.is_whitespace() || c == '/')
+                    .unwrap_or(inner.len());
+                let name = inner[..name_len].to_ascii_lowercase();
+                let self_closing = inner.ends_with('/');
+                let attrs = inner[name_len..].trim_end_matches('/');
+                if !self_closing && (name == "script" || name == "style") {
+                    self.raw_text_end = Some(name.clone());
+                }
+                return Some(HtmlToken::StartTag {
+                    raw,
+                    name,
+                    attrs,
+                    self_closing,
+                });
+            }
+        }
+
+        // Text runs up to the next `<` (a lone `<` is consumed as text)
+        let first = rest.chars().next().map_or(1, char::len_utf8);
+        let end = rest[first..]
+            .find('<')
+            .map_or(self.html.len(), |i| self.pos + first + i);
+        Some(HtmlToken::Text(self.advance(end)))
+    }
+}
+
+/// Collapse runs of whitespace in HTML text nodes to a single space
+///
+/// Tags are copied verbatim so attributes are never modified, and the
+/// contents of `<pre>`, `<code>`, `<textarea>`, `<script>` and `<style>`
+/// are preserved as-is.
+///
+/// # Arguments
+/// * `html` - HTML to clean up
+///
+/// # Returns
+/// HTML with collapsed whitespace
+#[pyfunction]
+fn collapse_html_whitespace(html: &str) -> String {
+    const PRESERVE: [&str; 5] = ["pre", "code", "textarea", "script", "style"];
+    let mut result = String::with_capacity(html.len());
+    let mut preserve_depth = 0usize;
+
+    for token in HtmlTokenizer::new(html) {
+        match &token {
+            HtmlToken::StartTag {
+                name,
+                self_closing: false,
+                ..
+            } if PRESERVE.contains(&name.as_str()) => {
+                preserve_depth += 1;
+            }
+            HtmlToken::EndTag { name, .. } if PRESERVE.contains(&name.as_str()) => {
+                preserve_depth = preserve_depth.saturating_sub(1);
+            }
+            HtmlToken::Text(text) if preserve_depth == 0 => {
+                let mut in_space = false;
+                for c in text.chars() {
+                    if c.is_whitespace() {
+                        if !in_space {
+                            result.push(' ');
+                        }
+                        in_space = true;
+                    } else {
+                        result.push(c);
+                        in_space = false;
+                    }
+                }
+                continue;
+            }
+            _ => {}
+        }
+        result.push_str(token.raw());
+    }
+
+    result
+}
+
+/// Escape text for use inside CSS strings and identifiers
+///
+/// Quotes, backslashes, angle brackets and control characters are
+/// replaced with CSS hex escapes (e.g. `"` becomes `\22 `), so AI text
+/// injected into a `style` attribute or a `content:` value cannot break
+/// out of it. NUL is replaced with U+FFFD as required by the CSS spec.
+///
+/// # Arguments
+/// * `text` - Text to escape
+///
+/// # Returns
+/// CSS-escaped text
+#[pyfunction]
+fn css_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\0' => escaped.push('\u{FFFD}'),
+            '"' | '\'' | '\\' | '<' | '>' | '\u{1}'..='\u{1F}' | '\u{7F}' => {
+                escaped.push_str(&format!("\\{:x} ", c as u32))
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Validate and canonicalize a base64 string
+///
+/// Strips whitespace (as found in line-wrapped base64), converts the
+/// URL-safe alphabet to the standard one and restores missing padding.
+///
+/// # Arguments
+/// * `data` - Base64 text to canonicalize
+///
+/// # Returns
+/// Canonical standard-alphabet, padded base64
+#[pyfunction]
+fn canonicalize_base64(data: &str) -> PyResult<String> {
+    let mut canonical: String = data
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| match c {
+            '-' => '+',
+            '_' => '/',
+            c => c,
+        })
+        .collect();
+
+    let unpadded_len = canonical.trim_end_matches('=').len();
+    if canonical.len() == unpadded_len {
+        match unpadded_len % 4 {
+            2 => canonical.push_str("=="),
+            3 => canonical.push('='),
+            _ => {}
+        }
+    }
+
+    let decoded = general_purpose::STANDARD
+        .decode(&canonical)
+        .map_err(|e| PyValueError::new_err(format!("Invalid base64 data: {}", e)))?;
+    Ok(general_purpose::STANDARD.encode(decoded))
+}
+
+/// Compute the decoded size of base64 data without decoding it
+///
+/// Accepts the same input as `canonicalize_base64`: whitespace is
+/// ignored, both the standard and URL-safe alphabets are allowed and
+/// padding is optional, but if present it must complete the last group,
+/// and the unused low bits of the final symbol must be zero.
+///
+/// # Arguments
+/// * `data` - Base64 text
+///
+/// # Returns
+/// Number of bytes the data decodes to, or a `ValueError` if its
+/// characters, length or padding are malformed
+#[pyfunction]
+fn base64_decoded_len(data: &str) -> PyResult<usize> {
+    let (mut len, mut padding, mut last) = (0usize, 0usize, 0u8);
+    for c in data.chars().filter(|c| !c.is_whitespace()) {
+        len += 1;
+        if c == '=' {
+            padding += 1;
+            continue;
+        }
+        if padding > 0 {
+            return Err(PyValueError::new_err(
+                "Invalid base64 data: padding before end",
+            ));
+        }
+        last = match c {
+            'A'..='Z' => c as u8 - b'A',
+            'a'..='z' => c as u8 - b'a' + 26,
+            '0'..='9' => c as u8 - b'0' + 52,
+            '+' | '-' => 62,
+            '/' | '_' => 63,
+            _ => {
+                return Err(PyValueError::new_err(format!(
+                    "Invalid base64 data: unexpected character {:?}",
+                    c
+                )))
+            }
+        };
+    }
+
+    if padding > 0 && (padding > 2 || len % 4 != 0) {
+        return Err(PyValueError::new_err("Invalid base64 data: bad padding"));
+    }
+    let data_len = len - padding;
+    // The final symbol of a partial group carries bits that must be unused
+    let (tail, unused_bits) = match data_len % 4 {
+        0 => (0, 0),
+        2 => (1, 0x0F),
+        3 => (2, 0x03),
+        _ => return Err(PyValueError::new_err("Invalid base64 data: bad length")),
+    };
+    if last & unused_bits != 0 {
+        return Err(PyValueError::new_err(
+            "Invalid base64 data: trailing bits set",
+        ));
+    }
+    Ok(data_len / 4 * 3 + tail)
+}
+
+/// Extract numbers and their units from text
+///
+/// Finds integer and decimal literals, with an optional leading sign,
+/// thousands separators and exponent, together with a unit immediately
+/// following them (`%`, `px`, `kb`...). Digits that are part of a word,
+/// like the `2` in `h2`, are skipped, and a `-` between two numbers is
+/// read as a range rather than a sign.
+///
+/// # Arguments
+/// * `text` - The text to search
+///
+/// # Returns
+/// List of (value, unit) tuples in order of appearance
+#[pyfunction]
+fn extract_numbers(text: &str) -> Vec<(f64, Option<String>)> {
+    NUMBER_RE
+        .captures_iter(text)
+        .filter_map(|cap| {
+            let start = cap.get(0).unwrap().start();
+            let prev = text[..start].chars().next_back();
+            // A `-` right after a digit separates a range like `3-5`
+            let is_range = &cap[1] == "-" && prev.is_some_and(|c| c.is_ascii_digit());
+            if !is_range && prev.is_some_and(|c| c.is_alphanumeric() || c == '.' || c == '_') {
+                return None;
+            }
+            let sign = if is_range { "" } else { &cap[1] };
+            let value: f64 = format!("{}{}", sign, cap[2].replace(',', ""))
+                .parse()
+                .ok()?;
+            Some((value, cap.get(3).map(|m| m.as_str().to_string())))
+        })
+        .collect()
+}
+
+/// A fenced code block found in markdown text
+struct FencedBlock {
+    /// 0-based index of the opening fence line
+    start_line: usize,
+    /// 0-based index of the closing fence line (the last line if unclosed)
+    end_line: usize,
+    /// Info string following the opening fence
+    info: String,
+    code: String,
+}
+
+/// Check whether `line` opens a fence, returning the fence and info string
+fn parse_fence_line(line: &str) -> Option<(&str, &str)> {
+    let trimmed = line.trim_start();
+    if line.len() - trimmed.len() > 3 {
+        return None;
+    }
+    let marker = trimmed.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let fence_len = trimmed.len() - trimmed.trim_start_matches(marker).len();
+    if fence_len < 3 {
+        return None;
+    }
+    let (fence, info) = trimmed.split_at(fence_len);
+    Some((fence, info.trim()))
+}
+
+/// Check whether `line` closes a block opened with `fence`
+///
+/// A block is closed by a bare fence of the same character that is at
+/// least as long as the opening one.
+fn closes_fence(line: &str, fence: &str) -> bool {
+    parse_fence_line(line)
+        .is_some_and(|(closing, rest)| rest.is_empty() && closing.starts_with(fence))
+}
+
+/// Find every fenced code block in markdown text
+///
+/// An unclosed block runs to the end of the text.
+fn scan_fenced_blocks(text: &str) -> Vec<FencedBlock> {
+    let mut blocks = Vec::new();
+    let mut lines = text.lines().enumerate();
+
+    while let Some((start_line, line)) = lines.next() {
+        let Some((fence, info)) = parse_fence_line(line) else {
+            continue;
+        };
+        let mut code_lines = Vec::new();
+        let mut end_line = start_line;
+        for (index, line) in lines.by_ref() {
+            end_line = index;
+            if closes_fence(line, fence) {
+                break;
+            }
+            code_lines.push(line);
+        }
+        blocks.push(FencedBlock {
+            start_line,
+            end_line,
+            info: info.to_string(),
+            code: code_lines.join("\n"),
+        });
+    }
+
+    blocks
+}
+
+/// Locate fenced code blocks and their source line numbers
+///
+/// # Arguments
+/// * `text` - Markdown text
+///
+/// # Returns
+/// List of (start_line, end_line, language, code) tuples, where the
+/// 1-based line numbers are those of the opening and closing fences
+#[pyfunction]
+fn locate_code_blocks(text: &str) -> Vec<(usize, usize, String, String)> {
+    scan_fenced_blocks(text)
+        .into_iter()
+        .map(|block| {
+            let language = block
+                .info
+                .split_whitespace()
+                .next()
+                .unwrap_or("")
+                .to_string();
+            (
+                block.start_line + 1,
+                block.end_line + 1,
+                language,
+                block.code,
+            )
+        })
+        .collect()
+}
+
+/// Escape text for safe inclusion in HTML content and attribute values
+fn escape_html_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Expand `{{key}}` placeholders in a template
+///
+/// All placeholders are replaced in a single scan, so values containing
+/// `{{...}}` are never expanded again. Unknown placeholders are left as-is.
+///
+/// # Arguments
+/// * `text` - Template text
+/// * `vars` - Mapping of placeholder names to values
+/// * `escape_html` - HTML-escape values before inserting them
+///
+/// # Returns
+/// The expanded text
+#[pyfunction]
+#[pyo3(signature = (text, vars, escape_html = false))]
+fn expand_template(text: &str, vars: HashMap<String, String>, escape_html: bool) -> String {
+    PLACEHOLDER_RE
+        .replace_all(text, |cap: &regex::Captures| match vars.get(&cap[1]) {
+            Some(value) if escape_html => escape_html_text(value),
+            Some(value) => value.clone(),
+            None => cap[0].to_string(),
+        })
+        .into_owned()
+}
+
+/// Guess the character encoding of a byte buffer
+///
+/// A byte order mark takes precedence; otherwise the encoding is
+/// guessed from the content with `chardetng`. Pure ASCII is reported
+/// as UTF-8.
+///
+/// # Arguments
+/// * `data` - Raw bytes, e.g. a fetched page without a declared charset
+///
+/// # Returns
+/// The encoding name (e.g. "UTF-8", "windows-1252", "Shift_JIS"), or
+/// None if the buffer is empty or no candidate is a convincing match
+#[pyfunction]
+fn detect_charset(data: &[u8]) -> Option<String> {
+    if data.is_empty() {
+        return None;
+    }
+    if let Some((encoding, _)) = encoding_rs::Encoding::for_bom(data) {
+        return Some(encoding.name().to_string());
+    }
+
+    let mut detector = chardetng::EncodingDetector::new();
+    if !detector.feed(data, true) {
+        return Some(encoding_rs::UTF_8.name().to_string());
+    }
+    let (encoding, confident) = detector.guess_assess(None, true);
+    confident.then(|| encoding.name().to_string())
+}
+
+/// Decode bytes in the named character encoding
+///
+/// Labels are resolved per the WHATWG Encoding Standard (so "latin1"
+/// means windows-1252), a leading BOM overrides the label, and
+/// malformed sequences are replaced with U+FFFD.
+///
+/// # Arguments
+/// * `data` - Raw bytes to decode
+/// * `charset` - Encoding label, e.g. as returned by `detect_charset`
+///
+/// # Returns
+/// The decoded text
+#[pyfunction]
+fn decode_bytes(data: &[u8], charset: &str) -> PyResult<String> {
+    let encoding = encoding_rs::Encoding::for_label(charset.trim().as_bytes())
+        .ok_or_else(|| PyValueError::new_err(format!("Unknown charset: {}", charset)))?;
+    let (text, _, _) = encoding.decode(data);
+    Ok(text.into_owned())
+}
+
+/// Iterate over the parsed attributes of every `<tag>` start tag in `html`
+fn tag_attributes<'a>(
+    html: &'a str,
+    tag: &'a str,
+) -> impl Iterator<Item = Vec<(String, String)>> + 'a {
+    HtmlTokenizer::new(html).filter_map(move |token| match token {
+        HtmlToken::StartTag { name, attrs, .. } if name == tag => Some(parse_attributes(attrs)),
+        _ => None,
+    })
+}
+
+/// Extract the page description from HTML meta tags
+///
+/// Looks at `<meta name="description">` and `<meta property="og:description">`,
+/// preferring the Open Graph description when both are present.
+///
+/// # Arguments
+/// * `html` - Page HTML
+///
+/// # Returns
+/// The decoded `content` attribute, or None if no description is declared
+#[pyfunction]
+fn extract_meta_description(html: &str) -> Option<String> {
+    let mut description = None;
+
+    for attrs in tag_attributes(html, "meta") {
+        let Some(content) = find_attribute(&attrs, "content") else {
+            continue;
+        };
+        let property = find_attribute(&attrs, "property");
+        if property.is_some_and(|p| p.eq_ignore_ascii_case("og:description")) {
+            return Some(content.trim().to_string());
+        }
+        if description.is_none()
+            && find_attribute(&attrs, "name").is_some_and(|n| n.eq_ignore_ascii_case("description"))
+        {
+            description = Some(content.trim().to_string());
+        }
+    }
+
+    description
+}
+
+/// Extract Open Graph metadata from HTML
+///
+/// Collects every `<meta property="og:...">` tag with a `content`
+/// attribute. Repeated properties such as multiple `og:image` tags are
+/// all kept.
+///
+/// # Arguments
+/// * `html` - Page HTML
+///
+/// # Returns
+/// List of (property, content) tuples in document order, with property
+/// names lowercased and content decoded
+#[pyfunction]
+fn extract_open_graph(html: &str) -> Vec<(String, String)> {
+    tag_attributes(html, "meta")
+        .filter_map(|attrs| {
+            let property = find_attribute(&attrs, "property")?
+                .trim()
+                .to_ascii_lowercase();
+            let content = find_attribute(&attrs, "content")?.trim().to_string();
+            property.starts_with("og:").then_some((property, content))
+        })
+        .collect()
+}
+
+/// Find the first `<meta name="viewport">` tag in HTML
+fn find_viewport_meta(html: &str) -> Option<Vec<(String, String)>> {
+    tag_attributes(html, "meta").find(|attrs| {
+        find_attribute(attrs, "name")
+            .is_some_and(|name| name.trim().eq_ignore_ascii_case("viewport"))
+    })
+}
+
+/// True if the page declares a `<meta name="viewport">` tag
+#[pyfunction]
+fn has_viewport_meta(html: &str) -> bool {
+    find_viewport_meta(html).is_some()
+}
+
+/// Extract the viewport declaration of a page
+///
+/// # Arguments
+/// * `html` - Page HTML
+///
+/// # Returns
+/// The `content` of the first `<meta name="viewport">` tag, e.g.
+/// `width=device-width, initial-scale=1`, or None if there is none
+#[pyfunction]
+fn extract_viewport(html: &str) -> Option<String> {
+    let attrs = find_viewport_meta(html)?;
+    find_attribute(&attrs, "content").map(|content| content.trim().to_string())
+}
+
+/// 64-bit FNV-1a hash, stable across runs and platforms
+fn fnv1a64(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+/// SplitMix64 finalizer, spreading similar inputs across all 64 bits
+fn mix64(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// Compute a 64-bit SimHash fingerprint of text
+///
+/// The text is lowercased and split into overlapping three-word
+/// shingles, so near-duplicate texts produce fingerprints with a small
+/// Hamming distance (see `hamming_distance`).
+///
+/// # Arguments
+/// * `text` - The text to fingerprint
+///
+/// # Returns
+/// The 64-bit fingerprint (0 for text without words)
+#[pyfunction]
+fn simhash(text: &str) -> u64 {
+    const SHINGLE: usize = 3;
+    let lower = text.to_lowercase();
+    let words: Vec<&str> = lower
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect();
+    if words.is_empty() {
+        return 0;
+    }
+
+    let mut weights = [0i64; 64];
+    for shingle in words.windows(SHINGLE.min(words.len())) {
+        let hash = mix64(fnv1a64(shingle.join(" ").as_bytes()));
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            if hash >> bit & 1 == 1 {
+                *weight += 1;
+            } else {
+                *weight -= 1;
+            }
+        }
+    }
+
+    weights
+        .iter()
+        .enumerate()
+        .filter(|(_, weight)| **weight > 0)
+        .fold(0, |fingerprint, (bit, _)| fingerprint | 1 << bit)
+}
+
+/// Count the bits that differ between two fingerprints
+///
+/// # Arguments
+/// * `a` - First fingerprint
+/// * `b` - Second fingerprint
+///
+/// # Returns
+/// The Hamming distance, from 0 (identical) to 64
+#[pyfunction]
+fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Remove inline markdown markup from a single line
+fn strip_inline_markdown(line: &str, keep_urls: bool) -> String {
+    let line = LINK_RE.replace_all(line, |cap: &regex::Captures| {
+        let (is_image, label, url) = (&cap[1] == "!", &cap[2], &cap[3]);
+        if keep_urls && !is_image && !url.is_empty() && url != label {
+            format!("{} ({})", label, url)
+        } else {
+            label.to_string()
+        }
+    });
+    EMPHASIS_MARKERS_RE
+        .replace_all(&line, |cap: &regex::Captures| {
+            // Exactly one alternative participates in each match
+            (1..cap.len())
+                .find_map(|i| cap.get(i))
+                .map_or("", |m| m.as_str())
+                .to_string()
+        })
+        .into_owned()
+}
+
+/// Remove blockquote and ATX heading markers from a markdown line
+fn strip_block_markers(line: &str) -> &str {
+    let mut line = line.trim();
+    while let Some(rest) = line.strip_prefix('>') {
+        line = rest.trim_start();
+    }
+    let heading = line.trim_start_matches('#');
+    if heading.len() < line.len() && (heading.is_empty() || heading.starts_with(' ')) {
+        line = heading.trim();
+    }
+    line
+}
+
+/// Convert markdown to plain text
+///
+/// Removes headings, emphasis, blockquote and code markers, flattens
+/// list items to `- item` lines and drops code fences while keeping the
+/// code itself.
+///
+/// # Arguments
+/// * `text` - Markdown text
+/// * `keep_urls` - Render links as `label (url)` instead of just `label`
+///
+/// # Returns
+/// Plain text
+#[pyfunction]
+fn markdown_to_plaintext(text: &str, keep_urls: bool) -> String {
+    let mut lines = Vec::new();
+    let mut fence: Option<String> = None;
+
+    for line in text.lines() {
+        if let Some(open) = &fence {
+            if closes_fence(line, open) {
+                fence = None;
+            } else {
+                lines.push(line.to_string());
+            }
+            continue;
+        }
+        if let Some((open, _)) = parse_fence_line(line) {
+            fence = Some(open.to_string());
+            continue;
+        }
+
+        let line = strip_block_markers(line);
+        match LIST_ITEM_RE.captures(line) {
+            Some(cap) => lines.push(format!("- {}", strip_inline_markdown(&cap[1], keep_urls))),
+            None => lines.push(strip_inline_markdown(line, keep_urls)),
+        }
+    }
+
+    lines.join("\n")
+}
+
+/// Check whether a string is a plausible email address
+///
+/// Implements a pragmatic subset of RFC 5322: an unquoted local part of
+/// atom characters separated by single dots, and a domain of at least
+/// two valid DNS labels. Quoted local parts and IP literals are rejected.
+///
+/// # Arguments
+/// * `email` - Address to validate
+///
+/// # Returns
+/// True if the address is valid
+#[pyfunction]
+fn is_valid_email(email: &str) -> bool {
+    const LOCAL_SPECIALS: &str = "!#$%&'*+/=?^_`{|}~-";
+
+    let Some((local, domain)) = email.split_once('@') else {
+        return false;
+    };
+    if local.is_empty() || local.len() > 64 || domain.len() > 253 {
+        return false;
+    }
+
+    let local_ok = local.split('.').all(|atom| {
+        !atom.is_empty()
+            && atom
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || LOCAL_SPECIALS.contains(c))
+    });
+
+    let labels: Vec<&str> = domain.split('.').collect();
+    let domain_ok = labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+        && labels
+            .last()
+            .is_some_and(|tld| tld.len() >= 2 && !tld.chars().all(|c| c.is_ascii_digit()));
+
+    local_ok && domain_ok
+}
+
+/// Split a command line into arguments, shell style
+///
+/// Follows POSIX shell quoting like Python's `shlex.split`: whitespace
+/// separates arguments, single quotes are literal, and backslashes
+/// escape the next character outside quotes (and `\`, `"`, `$`, `` ` ``
+/// inside double quotes).
+///
+/// # Arguments
+/// * `input` - Command line, e.g. `search "rust async" --limit 5`
+///
+/// # Returns
+/// List of arguments
+#[pyfunction]
+fn split_args(input: &str) -> PyResult<Vec<String>> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_arg = false;
+    let mut chars = input.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            '\'' => {
+                in_arg = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => current.push(c),
+                        None => return Err(PyValueError::new_err("Unterminated single quote")),
+                    }
+                }
+            }
+            '"' => {
+                in_arg = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('\\' | '"' | '$' | '`')) => current.push(c),
+                            Some('\n') => {}
+                            Some(c) => {
+                                current.push('\\');
+                                current.push(c);
+                            }
+                            None => return Err(PyValueError::new_err("Unterminated double quote")),
+                        },
+                        Some(c) => current.push(c),
+                        None => return Err(PyValueError::new_err("Unterminated double quote")),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some('\n') => {}
+                Some(c) => {
+                    in_arg = true;
+                    current.push(c);
+                }
+                None => {
+                    return Err(PyValueError::new_err(
+                        "No character after trailing backslash",
+                    ))
+                }
+            },
+            c => {
+                in_arg = true;
+                current.push(c);
+            }
+        }
+    }
+
+    if in_arg {
+        args.push(current);
+    }
+    Ok(args)
+}
+
+/// Measure the display width of text in terminal columns
+///
+/// Wide and fullwidth characters (such as CJK) count as two columns,
+/// and zero-width and combining characters count as none.
+///
+/// # Arguments
+/// * `text` - Text to measure
+///
+/// # Returns
+/// Width in columns
+#[pyfunction]
+fn display_width(text: &str) -> usize {
+    UnicodeWidthStr::width(text)
+}
+
+/// Wrap text so no line exceeds a given width
+///
+/// Each input line is wrapped on its own, so existing line breaks and
+/// blank lines are kept. Words are packed greedily and separated by
+/// single spaces; a word wider than `width` gets a line to itself
+/// rather than being split.
+///
+/// # Arguments
+/// * `text` - Text to wrap
+/// * `width` - Maximum line width
+/// * `use_display_width` - Measure terminal columns as `display_width`
+///   does instead of counting characters
+///
+/// # Returns
+/// The wrapped text
+#[pyfunction]
+#[pyo3(signature = (text, width, use_display_width=false))]
+fn wrap_text(text: &str, width: usize, use_display_width: bool) -> String {
+    let measure = |s: &str| {
+        if use_display_width {
+            UnicodeWidthStr::width(s)
+        } else {
+            s.chars().count()
+        }
+    };
+
+    let mut lines = Vec::new();
+    for line in text.lines() {
+        let mut current = String::new();
+        let mut current_width = 0;
+        for word in line.split_whitespace() {
+            let word_width = measure(word);
+            if !current.is_empty() && current_width + 1 + word_width > width {
+                lines.push(std::mem::take(&mut current));
+                current_width = 0;
+            }
+            if !current.is_empty() {
+                current.push(' ');
+                current_width += 1;
+            }
+            current.push_str(word);
+            current_width += word_width;
+        }
+        lines.push(current);
+    }
+    lines.join("\n")
+}
+
+/// Convert a character index into a byte offset
+///
+/// Python indexes strings by character while the native functions
+/// report byte offsets; this maps between the two. The index one past
+/// the last character maps to the length of the text.
+///
+/// # Arguments
+/// * `text` - The text being indexed
+/// * `char_index` - Index of a character, as used by Python slicing
+///
+/// # Returns
+/// The byte offset, or None if the index is out of range
+#[pyfunction]
+fn char_to_byte_offset(text: &str, char_index: usize) -> Option<usize> {
+    text.char_indices()
+        .map(|(offset, _)| offset)
+        .chain(std::iter::once(text.len()))
+        .nth(char_index)
+}
+
+/// Convert a byte offset into a character index
+///
+/// The inverse of `char_to_byte_offset`.
+///
+/// # Arguments
+/// * `text` - The text being indexed
+/// * `byte_offset` - Byte offset into the UTF-8 encoded text
+///
+/// # Returns
+/// The character index, or None if the offset is out of range or not
+/// on a character boundary
+#[pyfunction]
+fn byte_to_char_offset(text: &str, byte_offset: usize) -> Option<usize> {
+    if !text.is_char_boundary(byte_offset) {
+        return None;
+    }
+    Some(text[..byte_offset].chars().count())
+}
+
+/// Surround every match of a pattern with the given strings
+///
+/// A generalization of HTML highlighting for other contexts, such as
+/// ANSI color codes in a terminal or markdown emphasis. Empty matches
+/// are left alone.
+///
+/// # Arguments
+/// * `text` - The text to search
+/// * `pattern` - The regex pattern to match
+/// * `before` - String inserted before each match
+/// * `after` - String inserted after each match
+///
+/// # Returns
+/// The text with each non-overlapping match wrapped
+#[pyfunction]
+fn wrap_matches(text: &str, pattern: &str, before: &str, after: &str) -> PyResult<String> {
+    let re = Regex::new(pattern)
+        .map_err(|e| PyValueError::new_err(format!("Invalid regex pattern: {}", e)))?;
+
+    Ok(re
+        .replace_all(text, |cap: &regex::Captures| {
+            let matched = &cap[0];
+            if matched.is_empty() {
+                String::new()
+            } else {
+                format!("{}{}{}", before, matched, after)
+            }
+        })
+        .into_owned())
+}
+
+/// Split a markdown table row into trimmed cells
+///
+/// Leading and trailing pipes are optional and `\|` is a literal pipe.
+fn split_table_row(line: &str) -> Vec<String> {
+    let line = line.trim();
+    let line = line.strip_prefix('|').unwrap_or(line);
+    let line = match line.strip_suffix('|') {
+        Some(rest) if !rest.ends_with('\\') => rest,
+        _ => line,
+    };
+
+    let mut cells = Vec::new();
+    let mut cell = String::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('|') => cell.push('|'),
+                Some(other) => {
+                    cell.push('\\');
+                    cell.push(other);
+                }
+                None => cell.push('\\'),
+            },
+            '|' => cells.push(std::mem::take(&mut cell).trim().to_string()),
+            c => cell.push(c),
+        }
+    }
+    cells.push(cell.trim().to_string());
+    cells
+}
+
+/// Parse a pipe-delimited markdown table
+///
+/// The table must start with a header row followed by a separator row
+/// such as `| --- | :---: |`. Body rows end at the first line without
+/// a pipe and are padded or truncated to the header width.
+///
+/// # Arguments
+/// * `text` - Markdown table
+///
+/// # Returns
+/// A (header, rows) tuple of trimmed cells, or None if the text is not
+/// a valid table
+#[pyfunction]
+fn parse_markdown_table(text: &str) -> Option<(Vec<String>, Vec<Vec<String>>)> {
+    let mut lines = text
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| line.is_empty());
+
+    let header_line = lines.next().filter(|line| line.contains('|'))?;
+    let separator_line = lines.next().filter(|line| line.contains('-'))?;
+    let header = split_table_row(header_line);
+    let separator = split_table_row(separator_line);
+
+    let valid_separator = separator.iter().all(|cell| {
+        let dashes = cell.strip_prefix(':').unwrap_or(cell);
+        let dashes = dashes.strip_suffix(':').unwrap_or(dashes);
+        !dashes.is_empty() && dashes.chars().all(|c| c == '-')
+    });
+    if !valid_separator || separator.len() != header.len() {
+        return None;
+    }
+
+    let rows = lines
+        .take_while(|line| line.contains('|'))
+        .map(|line| {
+            let mut cells = split_table_row(line);
+            cells.resize(header.len(), String::new());
+            cells
+        })
+        .collect();
+
+    Some((header, rows))
+}
+
+/// Extract the lowercased scheme of a URL the way browsers parse it
+///
+/// Leading control characters and spaces are ignored and tabs/newlines
+/// are stripped, so obfuscated schemes like `java\tscript:` are still
+/// recognized. Returns None for scheme-relative and scheme-less URLs.
+fn url_scheme(url: &str) -> Option<String> {
+    let cleaned: String = url
+        .trim_start_matches(|c: char| c <= ' ')
+        .chars()
+        .filter(|c| !matches!(c, '\t' | '\n' | '\r'))
+        .collect();
+    let (scheme, _) = cleaned.split_once(':')?;
+
+    let valid_scheme = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+    valid_scheme.then(|| scheme.to_ascii_lowercase())
+}
+
+/// Check whether a URL uses a scheme that is safe to navigate to
+///
+/// The scheme is parsed the way browsers do, so obfuscated schemes like
+/// `java\tscript:` are recognized and rejected. Scheme-relative
+/// (`//host`) and scheme-less URLs are rejected too.
+///
+/// # Arguments
+/// * `url` - URL to check
+/// * `allowed_schemes` - Schemes to allow, defaults to http, https and file
+///
+/// # Returns
+/// True if the URL's scheme is allowed
+#[pyfunction]
+#[pyo3(signature = (url, allowed_schemes = None))]
+fn is_safe_navigation_url(url: &str, allowed_schemes: Option<HashSet<String>>) -> bool {
+    const DEFAULT_SCHEMES: [&str; 3] = ["http", "https", "file"];
+
+    let Some(scheme) = url_scheme(url) else {
+        return false;
+    };
+    match allowed_schemes {
+        Some(allowed) => allowed.iter().any(|s| s.eq_ignore_ascii_case(&scheme)),
+        None => DEFAULT_SCHEMES.contains(&scheme.as_str()),
+    }
+}
+
+/// Extract every capture group from the first match of a pattern
+///
+/// # Arguments
+/// * `text` - The text to search
+/// * `pattern` - The regex pattern to match
+///
+/// # Returns
+/// The groups of the first match, from group 0 (the whole match) to the
+/// last group, with None for groups that did not participate; None if
+/// there is no match
+#[pyfunction]
+fn extract_all_groups(text: &str, pattern: &str) -> PyResult<Option<Vec<Option<String>>>> {
+    let re = Regex::new(pattern)
+        .map_err(|e| PyValueError::new_err(format!("Invalid regex pattern: {}", e)))?;
+
+    Ok(re.captures(text).map(|cap| {
+        cap.iter()
+            .map(|group| group.map(|m| m.as_str().to_string()))
+            .collect()
+    }))
+}
+
+/// Largest LCS table `diff_sequences` builds (16 MB of `u32` cells)
+const MAX_LCS_CELLS: usize = 4_000_000;
+
+/// A single step of an edit script produced by `diff_sequences`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DiffOp {
+    /// Element `old[i]` is kept as `new[j]`
+    Equal(usize, usize),
+    /// Element `old[i]` is removed
+    Removed(usize),
+    /// Element `new[j]` is added
+    Added(usize),
+}
+
+/// Compute an LCS-based edit script turning `old` into `new`
+///
+/// Common prefixes and suffixes are skipped before building the LCS
+/// table, which keeps the common streaming case (text appended at the
+/// end) cheap. Removals are emitted before additions at each change.
+/// If the remaining middle would need a table larger than
+/// `MAX_LCS_CELLS`, it is reported as one coarse removal of the old
+/// middle followed by an addition of the new one.
+fn diff_sequences<T: PartialEq>(old: &[T], new: &[T]) -> Vec<DiffOp> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let a = &old[prefix..old.len() - suffix];
+    let b = &new[prefix..new.len() - suffix];
+    let (n, m) = (a.len(), b.len());
+
+    let mut ops: Vec<DiffOp> = (0..prefix).map(|i| DiffOp::Equal(i, i)).collect();
+    let suffix_ops = (0..suffix).map(|k| DiffOp::Equal(prefix + n + k, prefix + m + k));
+    if (n + 1).saturating_mul(m + 1) > MAX_LCS_CELLS {
+        ops.extend((0..n).map(|i| DiffOp::Removed(prefix + i)));
+        ops.extend((0..m).map(|j| DiffOp::Added(prefix + j)));
+        ops.extend(suffix_ops);
+        return ops;
+    }
+
+    // lcs[i * (m + 1) + j] is the LCS length of a[i..] and b[j..]
+    let mut lcs = vec![0u32; (n + 1) * (m + 1)];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i * (m + 1) + j] = if a[i] == b[j] {
+                lcs[(i + 1) * (m + 1) + j + 1] + 1
+            } else {
+                lcs[(i + 1) * (m + 1) + j].max(lcs[i * (m + 1) + j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if a[i] == b[j] {
+            ops.push(DiffOp::Equal(prefix + i, prefix + j));
+            i += 1;
+            j += 1;
+        } else if lcs[(i + 1) * (m + 1) + j] >= lcs[i * (m + 1) + j + 1] {
+            ops.push(DiffOp::Removed(prefix + i));
+            i += 1;
+        } else {
+            ops.push(DiffOp::Added(prefix + j));
+            j += 1;
+        }
+    }
+    ops.extend((i..n).map(|i| DiffOp::Removed(prefix + i)));
+    ops.extend((j..m).map(|j| DiffOp::Added(prefix + j)));
+    ops.extend(suffix_ops);
+    ops
+}
+
+/// Compute a word-level diff between two texts
+///
+/// Texts are compared as whitespace-separated tokens, which lets
+/// streaming UIs highlight inline changes as a response regenerates.
+///
+/// # Arguments
+/// * `old` - Previous text
+/// * `new` - Updated text
+///
+/// # Returns
+/// List of (op, token) tuples where op is "equal", "added" or "removed"
+#[pyfunction]
+fn word_diff(old: &str, new: &str) -> Vec<(String, String)> {
+    let old_tokens: Vec<&str> = old.split_whitespace().collect();
+    let new_tokens: Vec<&str> = new.split_whitespace().collect();
+
+    diff_sequences(&old_tokens, &new_tokens)
+        .into_iter()
+        .map(|op| match op {
+            DiffOp::Equal(i, _) => ("equal".to_string(), old_tokens[i].to_string()),
+            DiffOp::Removed(i) => ("removed".to_string(), old_tokens[i].to_string()),
+            DiffOp::Added(j) => ("added".to_string(), new_tokens[j].to_string()),
+        })
+        .collect()
+}
+
+/// Compute a line-level diff that ignores whitespace-only changes
+///
+/// Lines are compared with leading and trailing whitespace removed and
+/// internal runs collapsed, so re-indented or re-spaced lines count as
+/// unchanged.
+///
+/// # Arguments
+/// * `old` - Previous text
+/// * `new` - Updated text
+///
+/// # Returns
+/// List of (op, line) tuples where op is "equal", "added" or "removed";
+/// equal lines are reported as they appear in `new`
+#[pyfunction]
+fn line_diff_ignore_whitespace(old: &str, new: &str) -> Vec<(String, String)> {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let normalize = |line: &&str| line.split_whitespace().collect::<Vec<_>>().join(" ");
+    let old_keys: Vec<String> = old_lines.iter().map(normalize).collect();
+    let new_keys: Vec<String> = new_lines.iter().map(normalize).collect();
+
+    diff_sequences(&old_keys, &new_keys)
+        .into_iter()
+        .map(|op| match op {
+            DiffOp::Equal(_, j) => ("equal".to_string(), new_lines[j].to_string()),
+            DiffOp::Removed(i) => ("removed".to_string(), old_lines[i].to_string()),
+            DiffOp::Added(j) => ("added".to_string(), new_lines[j].to_string()),
+        })
+        .collect()
+}
+
+/// Check that HTML tags are properly closed and nested
+///
+/// Void elements such as `<br>` and `<img>` need no closing tag, and
+/// self-closing tags (`<div/>`) are accepted as closed.
+///
+/// # Arguments
+/// * `html` - HTML to check
+///
+/// # Returns
+/// True if every non-void element is closed in the right order
+#[pyfunction]
+fn is_balanced_html(html: &str) -> bool {
+    let mut open = Vec::new();
+
+    for token in HtmlTokenizer::new(html) {
+        match token {
+            HtmlToken::StartTag {
+                name,
+                self_closing: false,
+                ..
+            } if !VOID_ELEMENTS.contains(&name.as_str()) => open.push(name),
+            HtmlToken::EndTag { name, .. } if !VOID_ELEMENTS.contains(&name.as_str()) => {
+                let innermost = open.pop();
+                if innermost.as_deref() != Some(name.as_str()) {
+                    return false;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    open.is_empty()
+}
+
+/// Check whether a space-separated `rel` attribute contains `keyword`
+fn rel_contains(attrs: &[(String, String)], keyword: &str) -> bool {
+    find_attribute(attrs, "rel").is_some_and(|rel| {
+        rel.split_ascii_whitespace()
+            .any(|token| token.eq_ignore_ascii_case(keyword))
+    })
+}
+
+/// Resolve an `href` attribute against the page URL
+fn resolve_href(base: &Url, href: &str) -> Option<String> {
+    let href = href.trim();
+    if href.is_empty() {
+        return None;
+    }
+    base.join(href).ok().map(String::from)
+}
+
+/// Extract the favicon URL of a page
+///
+/// Uses the first `<link rel="icon">`, `rel="shortcut icon"` or
+/// `rel="apple-touch-icon"` tag, falling back to `/favicon.ico` when the
+/// page declares none.
+///
+/// # Arguments
+/// * `html` - Page HTML
+/// * `base_url` - URL of the page, used to resolve relative hrefs
+///
+/// # Returns
+/// The absolute favicon URL, or None if `base_url` is not a valid URL
+#[pyfunction]
+fn extract_favicon(html: &str, base_url: &str) -> Option<String> {
+    let base = Url::parse(base_url).ok()?;
+
+    tag_attributes(html, "link")
+        .filter(|attrs| rel_contains(attrs, "icon") || rel_contains(attrs, "apple-touch-icon"))
+        .find_map(|attrs| resolve_href(&base, find_attribute(&attrs, "href")?))
+        .or_else(|| resolve_href(&base, "/favicon.ico"))
+}
+
+/// Extract the canonical URL of a page
+///
+/// Uses the first `<link rel="canonical">` with an `href`, whatever the
+/// attribute order.
+///
+/// # Arguments
+/// * `html` - Page HTML
+/// * `base_url` - URL of the page, used to resolve relative hrefs
+///
+/// # Returns
+/// Absolute canonical URL, or `None` if the page declares none
+#[pyfunction]
+fn extract_canonical_url(html: &str, base_url: &str) -> Option<String> {
+    let base = Url::parse(base_url).ok()?;
+
+    tag_attributes(html, "link")
+        .filter(|attrs| rel_contains(attrs, "canonical"))
+        .find_map(|attrs| resolve_href(&base, find_attribute(&attrs, "href")?))
+}
+
+/// Resolve a relative URL against an absolute base URL
+///
+/// Follows the WHATWG URL Standard, so `../` segments, absolute paths
+/// and protocol-relative (`//host/path`) references behave as in a
+/// browser.
+///
+/// # Arguments
+/// * `base` - Absolute base URL
+/// * `relative` - URL reference to resolve
+///
+/// # Returns
+/// The resolved absolute URL
+#[pyfunction]
+fn resolve_url(base: &str, relative: &str) -> PyResult<String> {
+    let base = Url::parse(base)
+        .map_err(|e| PyValueError::new_err(format!("Invalid base URL '{}': {}", base, e)))?;
+    let resolved = base
+        .join(relative)
+        .map_err(|e| PyValueError::new_err(format!("Cannot resolve '{}': {}", relative, e)))?;
+    Ok(resolved.into())
+}
+
+/// Collapse whitespace runs in extracted text and trim it
+fn normalize_text(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Extract all links from HTML along with their anchor text
+///
+/// Relative hrefs are resolved against `base_url`; anchors without an
+/// href and `javascript:` links are skipped. Anchor text has nested
+/// tags removed, entities decoded and whitespace collapsed.
+///
+/// # Arguments
+/// * `html` - Page HTML
+/// * `base_url` - URL of the page, used to resolve relative hrefs
+///
+/// # Returns
+/// List of (absolute_url, anchor_text) tuples in document order
+#[pyfunction]
+fn extract_links(html: &str, base_url: &str) -> Vec<(String, String)> {
+    let base = Url::parse(base_url).ok();
+    let mut links = Vec::new();
+    let mut current: Option<(String, String)> = None;
+
+    for token in HtmlTokenizer::new(html) {
+        match token {
+            HtmlToken::StartTag { name, attrs, .. } if name == "a" => {
+                let attrs = parse_attributes(attrs);
+                current = find_attribute(&attrs, "href")
+                    .filter(|href| url_scheme(href).as_deref() != Some("javascript"))
+                    .and_then(|href| match &base {
+                        Some(base) => resolve_href(base, href),
+                        None => Url::parse(href.trim()).ok().map(String::from),
+                    })
+                    .map(|url| (url, String::new()));
+            }
+            HtmlToken::EndTag { name, .. } if name == "a" => {
+                if let Some((url, text)) = current.take() {
+                    links.push((url, normalize_text(&decode_html_entities(&text))));
+                }
+            }
+            HtmlToken::Text(text) => {
+                if let Some((_, anchor_text)) = current.as_mut() {
+                    anchor_text.push_str(text);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // An anchor left open at the end of the document still counts
+    if let Some((url, text)) = current {
+        links.push((url, normalize_text(&decode_html_entities(&text))));
+    }
+    links
+}
+
+/// Registrable domain of a host (e.g. `example.co.uk` for `www.example.co.uk`)
+///
+/// Falls back to the whole host for IP addresses and hosts the Public
+/// Suffix List doesn't cover.
+fn registrable_domain(host: &str) -> String {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    psl::domain_str(&host).unwrap_or(&host).to_string()
+}
+
+/// Split the links of a page into internal and external ones
+///
+/// A link is internal when its host has the same registrable domain as
+/// `base_url`, so subdomains of the site count as internal. Links without
+/// a host (`mailto:`, `javascript:`, `data:`) are left out.
+///
+/// # Arguments
+/// * `html` - Page HTML
+/// * `base_url` - URL of the page, used to resolve relative hrefs
+///
+/// # Returns
+/// `(internal, external)` lists of absolute URLs in document order
+#[pyfunction]
+fn classify_links(html: &str, base_url: &str) -> PyResult<(Vec<String>, Vec<String>)> {
+    let base = Url::parse(base_url)
+        .map_err(|e| PyValueError::new_err(format!("Invalid base URL '{}': {}", base_url, e)))?;
+    let site = base.host_str().map(registrable_domain);
+    let mut internal = Vec::new();
+    let mut external = Vec::new();
+
+    for attrs in tag_attributes(html, "a") {
+        let Some(href) = find_attribute(&attrs, "href") else {
+            continue;
+        };
+        let Ok(url) = base.join(href.trim()) else {
+            continue;
+        };
+        let Some(host) = url.host_str() else {
+            continue;
+        };
+        if site.as_deref() == Some(registrable_domain(host).as_str()) {
+            internal.push(url.into());
+        } else {
+            external.push(url.into());
+        }
+    }
+
+    Ok((internal, external))
+}
+
+/// Compute a minimal patch transforming one list into another
+///
+/// Operations are LCS-based and meant to be applied in order, with each
+/// index referring to the list as modified by the preceding operations.
+/// This lets the UI animate only the rows that changed.
+///
+/// # Arguments
+/// * `old` - Current list, e.g. of history URLs
+/// * `new` - Desired list
+///
+/// # Returns
+/// List of ("insert", index, value) and ("delete", index, "") operations
+#[pyfunction]
+fn list_patch(old: Vec<String>, new: Vec<String>) -> Vec<(String, usize, String)> {
+    let mut patch = Vec::new();
+    let mut position = 0;
+
+    for op in diff_sequences(&old, &new) {
+        match op {
+            DiffOp::Equal(_, _) => position += 1,
+            DiffOp::Removed(_) => patch.push(("delete".to_string(), position, String::new())),
+            DiffOp::Added(j) => {
+                patch.push(("insert".to_string(), position, new[j].clone()));
+                position += 1;
+            }
+        }
+    }
+
+    patch
+}
+
+/// Encode bytes as lowercase RFC 4648 base32 without padding
+fn base32_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for byte in bytes {
+        buffer = buffer << 8 | u32::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(ALPHABET[(buffer >> bits & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(ALPHABET[(buffer << (5 - bits) & 31) as usize] as char);
+    }
+    encoded
+}
+
+/// Compute a deterministic id for a conversation entry
+///
+/// Hashes the fields with SHA-256 (length-prefixed, so field boundaries
+/// can't be shifted to collide) and keeps the first 80 bits, which is
+/// plenty for deduplicating entries. Unlike Python's `hash()` the id is
+/// stable across runs.
+///
+/// # Arguments
+/// * `role` - Entry role, e.g. "user" or "assistant"
+/// * `content` - Entry content
+/// * `timestamp` - Entry timestamp
+///
+/// # Returns
+/// A 16-character lowercase base32 id
+#[pyfunction]
+fn entry_id(role: &str, content: &str, timestamp: i64) -> String {
+    let mut hasher = Sha256::new();
+    for field in [role.as_bytes(), content.as_bytes()] {
+        hasher.update((field.len() as u64).to_le_bytes());
+        hasher.update(field);
+    }
+    hasher.update(timestamp.to_le_bytes());
+    base32_encode(&hasher.finalize()[..10])
+}
+
+/// Detect a phrase repeated back-to-back, as when a model loops
+///
+/// A looping model keeps repeating itself until the end of the text, so
+/// only repetition that runs up to the last word counts. For each phrase
+/// length of at least `min_phrase_len` words, the trailing stretch that
+/// repeats with that period is measured; only the last 2048 words are
+/// inspected, which keeps the check cheap on every streamed chunk. The
+/// final copy may be cut off mid-phrase. Words are compared
+/// case-insensitively.
+///
+/// # Arguments
+/// * `text` - Text to inspect, e.g. a streaming response so far
+/// * `min_phrase_len` - Minimum phrase length in words (at least 1)
+/// * `min_repeats` - Minimum number of consecutive occurrences (at least 2)
+///
+/// # Returns
+/// The shortest phrase repeated at the end of the text, as first written
+/// in the repeating stretch, or None
+#[pyfunction]
+fn detect_repetition(text: &str, min_phrase_len: usize, min_repeats: usize) -> Option<String> {
+    const WINDOW: usize = 2048;
+    let min_phrase_len = min_phrase_len.max(1);
+    let min_repeats = min_repeats.max(2);
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let words = &words[words.len().saturating_sub(WINDOW)..];
+    let lower: Vec<String> = words.iter().map(|word| word.to_lowercase()).collect();
+    let n = lower.len();
+
+    for len in min_phrase_len..=n / min_repeats {
+        // Length of the trailing stretch where each word equals the one
+        // `len` words earlier
+        let matching = (len..n)
+            .rev()
+            .take_while(|&i| lower[i] == lower[i - len])
+            .count();
+        let stretch = len + matching;
+        if stretch >= min_repeats * len {
+            let start = n - stretch;
+            return Some(words[start..start + len].join(" "));
+        }
+    }
+
+    None
+}
+
+/// Detect the human language of a text
+///
+/// Uses trigram-based detection from `whatlang`. Inputs of fewer than
+/// three words are too short to classify reliably.
+///
+/// # Arguments
+/// * `text` - Text to classify, e.g. an AI response
+///
+/// # Returns
+/// A (ISO 639-3 code, confidence) tuple such as ("eng", 0.98), or None
+/// if the input is too short or the language can't be determined
+#[pyfunction]
+fn detect_language(text: &str) -> Option<(String, f64)> {
+    const MIN_WORDS: usize = 3;
+    if text.split_whitespace().count() < MIN_WORDS {
+        return None;
+    }
+    let info = whatlang::detect(text)?;
+    Some((info.lang().code().to_string(), info.confidence()))
+}
+
+/// Remove tracking query parameters from a URL
+///
+/// Strips `utm_*` parameters, common click ids (`fbclid`, `gclid`, ...)
+/// and any names in `extra_params`, compared case-insensitively. The
+/// remaining parameters keep their order and original encoding.
+///
+/// # Arguments
+/// * `url` - URL to clean
+/// * `extra_params` - Additional parameter names to remove
+///
+/// # Returns
+/// The serialized URL without tracking parameters
+#[pyfunction]
+fn clean_tracking_params(url: &str, extra_params: HashSet<String>) -> PyResult<String> {
+    let mut parsed = Url::parse(url)
+        .map_err(|e| PyValueError::new_err(format!("Invalid URL '{}': {}", url, e)))?;
+    let extra: HashSet<String> = extra_params.iter().map(|p| p.to_lowercase()).collect();
+
+    let Some(query) = parsed.query() else {
+        return Ok(parsed.into());
+    };
+    let kept: Vec<&str> = query
+        .split('&')
+        .filter(|pair| {
+            let Some((name, _)) = url::form_urlencoded::parse(pair.as_bytes()).next() else {
+                return false;
+            };
+            let name = name.to_lowercase();
+            !(name.starts_with("utm_")
+                || TRACKING_PARAMS.contains(&name.as_str())
+                || extra.contains(&name))
+        })
+        .collect();
+
+    let query = kept.join("&");
+    parsed.set_query((!query.is_empty()).then_some(query.as_str()));
+    Ok(parsed.into())
+}
+
+/// Collect the value of one capture group across all matches
+///
+/// # Arguments
+/// * `text` - The text to search
+/// * `pattern` - The regex pattern to match
+/// * `group` - Index of the capture group to collect (0 is the whole match)
+///
+/// # Returns
+/// The group's value for every match in which it participated
+#[pyfunction]
+fn group_match_values(text: &str, pattern: &str, group: usize) -> PyResult<Vec<String>> {
+    let re = Regex::new(pattern)
+        .map_err(|e| PyValueError::new_err(format!("Invalid regex pattern: {}", e)))?;
+    if group >= re.captures_len() {
+        return Err(PyValueError::new_err(format!(
+            "Group {} out of range: pattern has {} groups",
+            group,
+            re.captures_len() - 1
+        )));
+    }
+
+    Ok(re
+        .captures_iter(text)
+        .filter_map(|cap| cap.get(group))
+        .map(|m| m.as_str().to_string())
+        .collect())
+}
+
+/// Estimate the number of syllables in an English word
+///
+/// Counts groups of consecutive vowels, discounting a silent trailing
+/// `e` (but not `-le`, as in "table"). Every word has at least one.
+fn estimate_syllables(word: &str) -> usize {
+    let word: Vec<char> = word
+        .chars()
+        .filter(|c| c.is_alphabetic())
+        .flat_map(char::to_lowercase)
+        .collect();
+    let is_vowel = |c: &char| "aeiouy".contains(*c);
+
+    let mut syllables = 0;
+    let mut previous_vowel = false;
+    for c in &word {
+        let vowel = is_vowel(c);
+        if vowel && !previous_vowel {
+            syllables += 1;
+        }
+        previous_vowel = vowel;
+    }
+
+    if let [.., before, 'e'] = word[..] {
+        if syllables > 1 && !is_vowel(&before) && !(before == 'l' && word.len() > 2) {
+            syllables -= 1;
+        }
+    }
+    syllables.max(1)
+}
+
+/// Compute the Flesch Reading Ease score of a text
+///
+/// `206.835 - 1.015 * (words / sentences) - 84.6 * (syllables / words)`,
+/// with syllables estimated from vowel groups. Higher scores are easier
+/// to read: 90-100 is very easy, below 30 is very difficult. Text
+/// without sentence punctuation counts as a single sentence.
+///
+/// # Arguments
+/// * `text` - Text to score
+///
+/// # Returns
+/// The reading ease score, or 0.0 for text without words
+#[pyfunction]
+fn flesch_reading_ease(text: &str) -> f64 {
+    let words: Vec<&str> = text
+        .split_whitespace()
+        .filter(|word| word.chars().any(char::is_alphanumeric))
+        .collect();
+    if words.is_empty() {
+        return 0.0;
+    }
+
+    let sentences = text
+        .split(['.', '!', '?'])
+        .filter(|sentence| sentence.chars().any(char::is_alphanumeric))
+        .count()
+        .max(1);
+    let syllables: usize = words.iter().map(|word| estimate_syllables(word)).sum();
+
+    let words_per_sentence = words.len() as f64 / sentences as f64;
+    let syllables_per_word = syllables as f64 / words.len() as f64;
+    206.835 - 1.015 * words_per_sentence - 84.6 * syllables_per_word
+}
+
+/// Build an HTML attribute string from name/value pairs
+///
+/// Values are escaped for double-quoted attributes (`&`, `<`, `>`, `"`
+/// and newlines), so the result can be dropped into a start tag.
+///
+/// # Arguments
+/// * `pairs` - List of (name, value) tuples
+///
+/// # Returns
+/// Space-separated `name="value"` pairs
+#[pyfunction]
+fn build_attributes(pairs: Vec<(String, String)>) -> PyResult<String> {
+    let mut attributes = Vec::with_capacity(pairs.len());
+
+    for (name, value) in pairs {
+        let valid_name = !name.is_empty()
+            && !name.chars().any(|c| {
+                c.is_whitespace()
+                    || c.is_control()
+                    || matches!(c, '"' | '\'' | '<' | '>' | '/' | '=')
+            });
+        if !valid_name {
+            return Err(PyValueError::new_err(format!(
+                "Invalid attribute name: {:?}",
+                name
+            )));
+        }
+
+        let mut escaped = String::with_capacity(value.len());
+        for c in value.chars() {
+            match c {
+                '&' => escaped.push_str("&amp;"),
+                '<' => escaped.push_str("&lt;"),
+                '>' => escaped.push_str("&gt;"),
+                '"' => escaped.push_str("&quot;"),
+                '\n' => escaped.push_str("&#10;"),
+                '\r' => escaped.push_str("&#13;"),
+                c => escaped.push(c),
+            }
+        }
+        attributes.push(format!("{}=\"{}\"", name, escaped));
+    }
+
+    Ok(attributes.join(" "))
+}
+
+/// Extract the raw contents of every JSON-LD block in HTML
+///
+/// Collects `<script type="application/ld+json">` blocks (the type is
+/// matched case-insensitively) without parsing them, leaving it to the
+/// caller to parse the JSON it needs.
+///
+/// # Arguments
+/// * `html` - Page HTML
+///
+/// # Returns
+/// List of trimmed JSON strings in document order
+#[pyfunction]
+fn extract_jsonld(html: &str) -> Vec<String> {
+    let mut blocks = Vec::new();
+    let mut tokens = HtmlTokenizer::new(html);
+
+    while let Some(token) = tokens.next() {
+        let HtmlToken::StartTag { name, attrs, .. } = token else {
+            continue;
+        };
+        let is_jsonld = name == "script"
+            && find_attribute(&parse_attributes(attrs), "type")
+                .is_some_and(|t| t.trim().eq_ignore_ascii_case("application/ld+json"));
+        if !is_jsonld {
+            continue;
+        }
+        if let Some(HtmlToken::Text(content)) = tokens.next() {
+            let content = content.trim();
+            if !content.is_empty() {
+                blocks.push(content.to_string());
+            }
+        }
+    }
+
+    blocks
+}
+
+/// Format a byte count as a human-readable size
+///
+/// # Arguments
+/// * `bytes` - Size in bytes
+/// * `binary` - Use binary units (KiB, MiB, ... base 1024) instead of
+///   decimal ones (KB, MB, ... base 1000)
+///
+/// # Returns
+/// A string like "1.5 MB", with one decimal place above the byte range
+#[pyfunction]
+fn format_bytes(bytes: u64, binary: bool) -> String {
+    let (base, units) = if binary {
+        (1024.0, ["B", "KiB", "MiB", "GiB", "TiB"])
+    } else {
+        (1000.0, ["B", "KB", "MB", "GB", "TB"])
+    };
+    if (bytes as f64) < base {
+        return format!("{} B", bytes);
+    }
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    // Compare the rounded value so 999.96 KB is shown as 1.0 MB
+    while unit < units.len() - 1 && (value * 10.0).round() / 10.0 >= base {
+        value /= base;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, units[unit])
+}
+
+/// Compute a cache key for a URL
+///
+/// The URL is normalized before hashing: the scheme and host are
+/// lowercased (by URL parsing), the fragment is dropped and query
+/// parameters are sorted, so URLs that only differ in those respects
+/// share a key.
+///
+/// # Arguments
+/// * `url` - URL of the cached page
+///
+/// # Returns
+/// Hex-encoded SHA-256 of the normalized URL
+#[pyfunction]
+fn url_cache_key(url: &str) -> PyResult<String> {
+    let mut parsed = Url::parse(url)
+        .map_err(|e| PyValueError::new_err(format!("Invalid URL '{}': {}", url, e)))?;
+    parsed.set_fragment(None);
+
+    if let Some(query) = parsed.query() {
+        let mut params: Vec<&str> = query.split('&').filter(|p| !p.is_empty()).collect();
+        params.sort_unstable();
+        let query = params.join("&");
+        parsed.set_query((!query.is_empty()).then_some(query.as_str()));
+    }
+
+    Ok(format!("{:x}", Sha256::digest(parsed.as_str().as_bytes())))
+}
+
+/// Guess a title for a response from its first words
+///
+/// Takes the first non-empty line outside code blocks, strips its
+/// markdown and keeps up to `max_words` words, capitalizing the first
+/// letter and appending `…` when words were dropped.
+///
+/// # Arguments
+/// * `text` - Response text
+/// * `max_words` - Maximum number of words in the title
+///
+/// # Returns
+/// The title, or an empty string if the text has no prose or
+/// `max_words` is 0
+#[pyfunction]
+fn title_from_text(text: &str, max_words: usize) -> String {
+    if max_words == 0 {
+        return String::new();
+    }
+    let mut fence: Option<&str> = None;
+    let mut first_line = None;
+
+    for line in text.lines() {
+        if let Some(open) = fence {
+            if closes_fence(line, open) {
+                fence = None;
+            }
+            continue;
+        }
+        if let Some((open, _)) = parse_fence_line(line) {
+            fence = Some(open);
+            continue;
+        }
+        if is_horizontal_rule(line) {
+            continue;
+        }
+        let line = strip_block_markers(line);
+        let line = LIST_ITEM_RE
+            .captures(line)
+            .map_or(line, |cap| cap.get(1).unwrap().as_str());
+        let line = strip_inline_markdown(line, false);
+        if !line.trim().is_empty() {
+            first_line = Some(line);
+            break;
+        }
+    }
+
+    let Some(line) = first_line else {
+        return String::new();
+    };
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let mut title = words[..words.len().min(max_words)].join(" ");
+    if let Some(first) = title.chars().next() {
+        title = first.to_uppercase().chain(title.chars().skip(1)).collect();
+    }
+    if words.len() > max_words {
+        title.push('…');
+    }
+    title
+}
+
+/// Validate a color value and convert it to `#rrggbb`
+///
+/// Accepts `#rgb`, `#rrggbb`, `rgb(r, g, b)` (comma or space separated,
+/// channels clamped to 0-255) and the CSS 2.1 named colors, all
+/// case-insensitively.
+///
+/// # Arguments
+/// * `input` - Color from a theme, the user or the AI
+///
+/// # Returns
+/// The canonical lowercase `#rrggbb` color, or None if invalid
+#[pyfunction]
+fn normalize_color(input: &str) -> Option<String> {
+    let color = input.trim().to_ascii_lowercase();
+
+    if let Some(hex) = color.strip_prefix('#') {
+        if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        return match hex.len() {
+            3 => Some(format!(
+                "#{}",
+                hex.chars().flat_map(|c| [c, c]).collect::<String>()
+            )),
+            6 => Some(color),
+            _ => None,
+        };
+    }
+
+    if let Some(args) = color
+        .strip_prefix("rgb(")
+        .and_then(|rest| rest.strip_suffix(')'))
+    {
+        let channels: Vec<f64> = args
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|part| !part.is_empty())
+            .map(|part| part.parse::<f64>())
+            .collect::<Result<_, _>>()
+            .ok()?;
+        if channels.len() != 3 || channels.iter().any(|c| !c.is_finite()) {
+            return None;
+        }
+        let [r, g, b] =
+            [channels[0], channels[1], channels[2]].map(|c| c.round().clamp(0.0, 255.0) as u8);
+        return Some(format!("#{:02x}{:02x}{:02x}", r, g, b));
+    }
+
+    NAMED_COLORS
+        .iter()
+        .find(|(name, _)| *name == color)
+        .map(|(_, hex)| hex.to_string())
+}
+
+/// Extract the stylesheet URLs a page depends on
+///
+/// Collects `<link rel="stylesheet" href>` tags and `@import` rules in
+/// inline `<style>` blocks (whose own rules have no URL and are
+/// skipped), resolving each against `base_url`.
+///
+/// # Arguments
+/// * `html` - Page HTML
+/// * `base_url` - URL of the page, used to resolve relative URLs
+///
+/// # Returns
+/// Absolute stylesheet URLs in document order, or an empty list if
+/// `base_url` is not a valid URL
+#[pyfunction]
+fn extract_stylesheets(html: &str, base_url: &str) -> Vec<String> {
+    let Ok(base) = Url::parse(base_url) else {
+        return Vec::new();
+    };
+    let mut stylesheets = Vec::new();
+    let mut in_style = false;
+
+    for token in HtmlTokenizer::new(html) {
+        match token {
+            HtmlToken::StartTag { name, attrs, .. } if name == "link" => {
+                let attrs = parse_attributes(attrs);
+                if rel_contains(&attrs, "stylesheet") {
+                    let href = find_attribute(&attrs, "href");
+                    stylesheets.extend(href.and_then(|href| resolve_href(&base, href)));
+                }
+            }
+            HtmlToken::StartTag { name, .. } if name == "style" => in_style = true,
+            HtmlToken::EndTag { name, .. } if name == "style" => in_style = false,
+            HtmlToken::Text(css) if in_style => {
+                stylesheets.extend(
+                    CSS_IMPORT_RE
+                        .captures_iter(css)
+                        .filter_map(|cap| resolve_href(&base, &cap[1])),
+                );
+            }
+            _ => {}
+        }
+    }
+
+    stylesheets
+}
+
+/// Merge several keyword sets into one
+///
+/// Centralizes keyword preparation before calling `fast_string_contains`,
+/// which expects lowercase keywords.
+///
+/// # Arguments
+/// * `sets` - Keyword sets from different intent configs
+/// * `lowercase` - Lowercase every keyword before merging
+///
+/// # Returns
+/// The deduplicated union of all sets
+#[pyfunction]
+fn merge_keyword_sets(sets: Vec<HashSet<String>>, lowercase: bool) -> HashSet<String> {
+    let capacity = sets.iter().map(HashSet::len).max().unwrap_or(0);
+    let mut merged = HashSet::with_capacity(capacity);
+    for keyword in sets.into_iter().flatten() {
+        if lowercase {
+            merged.insert(keyword.to_lowercase());
+        } else {
+            merged.insert(keyword);
+        }
+    }
+    merged
+}
+
+/// Summarize the makeup of a response in a single pass
+///
+/// Lets the renderer pick a layout without scanning the response once
+/// per measurement. Code is the content of fenced blocks; fence lines
+/// and line breaks are not counted as either prose or code.
+///
+/// # Arguments
+/// * `text` - Response text
+///
+/// # Returns
+/// A (prose_char_count, code_char_count, url_count) tuple, where URLs
+/// are bare http(s) links in prose
+#[pyfunction]
+fn analyze_response(text: &str) -> (usize, usize, usize) {
+    let (mut prose, mut code, mut urls) = (0, 0, 0);
+    let mut fence: Option<&str> = None;
+
+    for line in text.lines() {
+        match fence {
+            Some(open) if closes_fence(line, open) => fence = None,
+            Some(_) => code += line.chars().count(),
+            None => match parse_fence_line(line) {
+                Some((open, _)) => fence = Some(open),
+                None => {
+                    prose += line.chars().count();
+                    urls += BARE_URL_RE.find_iter(line).count();
+                }
+            },
+        }
+    }
+
+    (prose, code, urls)
+}
+
+/// Escape markdown metacharacters so text renders literally
+///
+/// Backslash-escapes `\`, `*`, `_`, `` ` ``, `[`, `]`, `(`, `)`, `#`,
+/// `>`, `|`, `~`, `$` and `^` for inserting user text into generated
+/// markdown.
+///
+/// # Arguments
+/// * `text` - Literal text
+///
+/// # Returns
+/// Markdown that renders as the original text
+#[pyfunction]
+fn markdown_escape(text: &str) -> String {
+    const SPECIAL: &str = "\\*_`[]()#>|~$^";
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if SPECIAL.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Shuffle items reproducibly from a seed
+///
+/// Uses ChaCha8, whose output is stable across platforms and releases,
+/// so the same seed always yields the same order (e.g. for A/B
+/// ordering of response candidates).
+///
+/// # Arguments
+/// * `items` - Items to shuffle
+/// * `seed` - PRNG seed
+///
+/// # Returns
+/// The items in shuffled order
+#[pyfunction]
+fn seeded_shuffle(mut items: Vec<String>, seed: u64) -> Vec<String> {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    items.shuffle(&mut rng);
+    items
+}
+
+/// Estimate the number of tokens in a text
+///
+/// Uses the common rule of thumb of about four characters per token
+/// for English text with BPE tokenizers. Good enough for budgeting, not
+/// for billing.
+fn approximate_token_count(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Trim a conversation to fit a token budget
+///
+/// Drops the oldest messages until the estimated total (see
+/// `approximate_token_count`) fits within `max_tokens`. The most recent
+/// message is always kept, even if it alone exceeds the budget.
+///
+/// # Arguments
+/// * `messages` - Messages, oldest first
+/// * `max_tokens` - Token budget
+///
+/// # Returns
+/// The most recent messages that fit, oldest first
+#[pyfunction]
+fn trim_to_budget(messages: Vec<String>, max_tokens: usize) -> Vec<String> {
+    let counts: Vec<usize> = messages
+        .iter()
+        .map(|m| approximate_token_count(m))
+        .collect();
+    let mut total: usize = counts.iter().sum();
+    let mut start = 0;
+    while total > max_tokens && start + 1 < messages.len() {
+        total -= counts[start];
+        start += 1;
+    }
+    messages.into_iter().skip(start).collect()
+}
+
+/// Compute the dominant color of an image
+///
+/// The image is downsampled to at most 64x64, mostly-transparent pixels
+/// are ignored and the remaining colors are quantized to 4 bits per
+/// channel. The result is the average color of the most populated
+/// bucket, with ties broken by bucket order so the result is stable for
+/// a given image.
+///
+/// # Arguments
+/// * `image_bytes` - Encoded image (PNG, JPEG, GIF, WebP, BMP or ICO)
+///
+/// # Returns
+/// The dominant color as `#rrggbb`
+#[pyfunction]
+fn dominant_color(image_bytes: &[u8]) -> PyResult<String> {
+    let image = image::load_from_memory(image_bytes)
+        .map_err(|e| PyValueError::new_err(format!("Cannot decode image: {}", e)))?;
+    let pixels = image
+        .resize(64, 64, image::imageops::FilterType::Nearest)
+        .to_rgba8();
+
+    let mut buckets: HashMap<(u8, u8, u8), (u32, [u32; 3])> = HashMap::new();
+    let opaque: Vec<&image::Rgba<u8>> = pixels.pixels().filter(|p| p[3] >= 128).collect();
+    let sampled = if opaque.is_empty() {
+        pixels.pixels().collect()
+    } else {
+        opaque
+    };
+    for pixel in sampled {
+        let [r, g, b, _] = pixel.0;
+        let (count, sums) = buckets.entry((r >> 4, g >> 4, b >> 4)).or_default();
+        *count += 1;
+        for (sum, channel) in sums.iter_mut().zip([r, g, b]) {
+            *sum += u32::from(channel);
+        }
+    }
+
+    let (_, (count, sums)) = buckets
+        .into_iter()
+        .max_by(|(key_a, (a, _)), (key_b, (b, _))| a.cmp(b).then(key_b.cmp(key_a)))
+        .ok_or_else(|| PyValueError::new_err("Image has no pixels"))?;
+    let [r, g, b] = sums.map(|sum| (sum as f64 / count as f64).round() as u8);
+    Ok(format!("#{:02x}{:02x}{:02x}", r, g, b))
+}
+
+/// Parse a raw HTTP header block
+///
+/// Lines may end in CRLF or LF. A leading status or request line is
+/// skipped, obsolete line folding (continuation lines starting with
+/// whitespace) is joined with a single space, and parsing stops at the
+/// first empty line. Lines that aren't valid `Name: value` pairs are
+/// ignored, along with any continuation lines that follow them.
+///
+/// # Arguments
+/// * `raw` - Raw header block
+///
+/// # Returns
+/// List of (name, value) tuples with names as sent and trimmed values
+#[pyfunction]
+fn parse_headers(raw: &str) -> Vec<(String, String)> {
+    let mut headers: Vec<(String, String)> = Vec::new();
+    // Whether the last unfolded line was kept, so continuations belong to it
+    let mut folding = false;
+
+    for (index, line) in raw.split('\n').enumerate() {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if line.is_empty() {
+            if index == 0 {
+                continue;
+            }
+            break;
+        }
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = headers.last_mut().filter(|_| folding) {
+                let continuation = line.trim();
+                if !continuation.is_empty() {
+                    if !value.is_empty() {
+                        value.push(' ');
+                    }
+                    value.push_str(continuation);
+                }
+            }
+            continue;
+        }
+        folding = false;
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let valid_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?={}".contains(c));
+        if valid_name {
+            headers.push((name.to_string(), value.trim().to_string()));
+            folding = true;
+        }
+    }
+
+    headers
+}
+
+/// Check whether two URLs share the same origin
+///
+/// Compares scheme, host and port, with default ports applied, so
+/// `https://x.com` and `https://x.com:443` are same-origin. URLs with
+/// opaque origins (such as `data:` or `file:`) never match.
+///
+/// # Arguments
+/// * `a` - First URL
+/// * `b` - Second URL
+///
+/// # Returns
+/// True if both URLs have the same origin
+#[pyfunction]
+fn same_origin(a: &str, b: &str) -> PyResult<bool> {
+    let parse = |url: &str| {
+        Url::parse(url).map_err(|e| PyValueError::new_err(format!("Invalid URL '{}': {}", url, e)))
+    };
+    Ok(parse(a)?.origin() == parse(b)?.origin())
+}
+
+/// Check whether an SVG attribute name refers to a link
+fn is_svg_link_attribute(name: &str) -> bool {
+    let name = name.trim().to_ascii_lowercase();
+    // Any prefix can be bound to the XLink namespace, not just `xlink:`
+    matches!(name.as_str(), "href" | "src") || name.ends_with(":href")
+}
+
+/// Check whether an SVG attribute can run script or load an external resource
+///
+/// `animates_link` is set for animation elements whose `attributeName`
+/// is a link, whose `to`/`from`/`by`/`values` then follow link rules.
+fn is_unsafe_svg_attribute(name: &str, value: &str, animates_link: bool) -> bool {
+    let name = name.to_ascii_lowercase();
+    let value = decode_html_entities(value);
+    let value = value.trim().to_ascii_lowercase();
+    if name.starts_with("on") {
+        return true;
+    }
+    if is_svg_link_attribute(&name) && !value.starts_with('#') {
+        return true;
+    }
+    // `<animate>`/`<set>` can swap an `href` for another URL at runtime
+    let animation_value = matches!(name.as_str(), "to" | "from" | "by" | "values");
+    if animation_value
+        && animates_link
+        && value.split(';').any(|item| !item.trim().starts_with('#'))
+    {
+        return true;
+    }
+    let compact: String = value
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .collect();
+    if animation_value && compact.contains("javascript:") {
+        return true;
+    }
+    value.match_indices("url(").any(|(i, m)| {
+        !value[i + m.len()..]
+            .trim_start_matches(['"', '\'', ' '])
+            .starts_with('#')
+    })
+}
+
+/// Skip the XML declaration, processing instructions, comments and
+/// `<!DOCTYPE>` (including any internal `[...]` subset) before the root
+fn skip_xml_prolog(svg: &str) -> &str {
+    let mut rest = svg.trim_start();
+    loop {
+        let end = if rest.starts_with("<?") {
+            rest.find("?>").map(|i| i + 2)
+        } else if rest.starts_with("<!--") {
+            rest.find("-->").map(|i| i + 3)
+        } else if rest
+            .get(..9)
+            .is_some_and(|s| s.eq_ignore_ascii_case("<!doctype"))
+        {
+            // The internal subset may declare entities, which contain `>`
+            let subset = rest
+                .find(['[', '>'])
+                .filter(|&i| rest.as_bytes()[i] == b'[');
+            let from = subset.map_or(Some(0), |i| rest[i..].find(']').map(|j| i + j));
+            from.and_then(|from| rest[from..].find('>').map(|i| from + i + 1))
+        } else {
+            return rest;
+        };
+        match end {
+            Some(end) => rest = rest[end..].trim_start(),
+            None => return "",
+        }
+    }
+}
+
+/// Build a sanitized `data:image/svg+xml` URL from SVG markup
+///
+/// Drops the XML prolog (including any `<!DOCTYPE>` and its entity
+/// declarations), processing instructions, `<script>` and `<style>`
+/// elements, `on*` event handler attributes, `javascript:` animation
+/// values and any reference to an external resource (`href`, `*:href`
+/// and `src` values that aren't `#fragment` links, animations of those
+/// attributes to anything else, and `url(...)` values pointing outside
+/// the document). Presentation
+/// attributes are kept, so styling survives without `<style>`. The
+/// result is percent-encoded rather than base64-encoded, which keeps it
+/// shorter and readable.
+///
+/// # Arguments
+/// * `svg` - SVG markup, optionally preceded by an XML declaration
+///
+/// # Returns
+/// A `data:image/svg+xml` URL, or a `ValueError` if the input isn't SVG
+#[pyfunction]
+fn svg_to_data_url(svg: &str) -> PyResult<String> {
+    let svg = skip_xml_prolog(svg);
+    let root = HtmlTokenizer::new(svg).find_map(|token| match token {
+        HtmlToken::StartTag { name, .. } => Some(name),
+        HtmlToken::Text(text) if !text.trim().is_empty() => Some(String::new()),
+        _ => None,
+    });
+    if root.as_deref() != Some("svg") {
+        return Err(PyValueError::new_err("Input is not an SVG document"));
+    }
+
+    let mut sanitized = String::with_capacity(svg.len());
+    // `<style>` can pull in external resources through `@import` and `url()`
+    const DROPPED: [&str; 2] = ["script", "style"];
+    let mut dropping = false;
+    for token in HtmlTokenizer::new(svg) {
+        match token {
+            HtmlToken::StartTag {
+                name, self_closing, ..
+            } if DROPPED.contains(&name.as_str()) => dropping = !self_closing,
+            HtmlToken::EndTag { name, .. } if DROPPED.contains(&name.as_str()) => dropping = false,
+            _ if dropping => {}
+            // Markup declarations such as `<!ENTITY>` and processing instructions
+            HtmlToken::Other(raw)
+                if raw.starts_with("<?")
+                    || (raw.starts_with("<!")
+                        && !raw.starts_with("<!--")
+                        && !raw.starts_with("<![")) => {}
+            HtmlToken::StartTag {
+                raw,
+                attrs,
+                self_closing,
+                ..
+            } => {
+                let name_end = raw[1..]
+                    .find(|c: char| c.is_whitespace() || c == '/' || c == '>')
+                    .map_or(raw.len(), |i| i + 1);
+                sanitized.push_str(&raw[..name_end]);
+                let animates_link = find_attribute(&parse_attributes(attrs), "attributename")
+                    .is_some_and(is_svg_link_attribute);
+                for cap in ATTR_RE.captures_iter(attrs) {
+                    let value = cap
+                        .get(2)
+                        .or_else(|| cap.get(3))
+                        .or_else(|| cap.get(4))
+                        .map_or("", |m| m.as_str());
+                    if !is_unsafe_svg_attribute(&cap[1], value, animates_link) {
+                        sanitized.push(' ');
+                        sanitized.push_str(&cap[0]);
+                    }
+                }
+                sanitized.push_str(if self_closing { "/>" } else { ">" });
+            }
+            token => sanitized.push_str(token.raw()),
+        }
+    }
+
+    let mut url = String::from("data:image/svg+xml,");
+    for byte in sanitized.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@/".contains(&byte) {
+            url.push(byte as char);
+        } else {
+            url.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    Ok(url)
+}
+
+/// Split text into sections at explicit marker lines
+///
+/// A marker is a line starting with `marker_prefix` (e.g. `"### SECTION:"`);
+/// the rest of the line, trimmed, names the section that follows. Text
+/// before the first marker is returned under an empty name, and is
+/// omitted when it's blank.
+///
+/// # Arguments
+/// * `text` - Text to split
+/// * `marker_prefix` - Prefix identifying marker lines
+///
+/// # Returns
+/// `(section_name, body)` pairs in order, with bodies trimmed of
+/// surrounding blank lines
+#[pyfunction]
+fn split_sections(text: &str, marker_prefix: &str) -> Vec<(String, String)> {
+    let mut sections = Vec::new();
+    let mut name = String::new();
+    let mut body: Vec<&str> = Vec::new();
+
+    let mut flush = |name: String, body: &[&str], leading: bool| {
+        let content = body.join("\n").trim_matches('\n').to_string();
+        if !(leading && content.trim().is_empty()) {
+            sections.push((name, content));
+        }
+    };
+
+    let mut leading = true;
+    for line in text.lines() {
+        match line.strip_prefix(marker_prefix) {
+            Some(rest) if !marker_prefix.is_empty() => {
+                flush(std::mem::take(&mut name), &body, leading);
+                name = rest.trim().to_string();
+                body.clear();
+                leading = false;
+            }
+            _ => body.push(line),
+        }
+    }
+    flush(name, &body, leading);
+
+    sections
+}
+
+/// True for characters that would be invisible or misleading in the address bar
+///
+/// Covers whitespace, control characters, bidi controls (which can
+/// reorder the rest of the URL) and zero-width or otherwise invisible
+/// characters.
+fn is_hidden_in_display(c: char) -> bool {
+    c.is_whitespace()
+        || c.is_control()
+        || matches!(
+            c,
+            '\u{00AD}'
+                | '\u{034F}'
+                | '\u{061C}'
+                | '\u{115F}'
+                | '\u{1160}'
+                | '\u{17B4}'
+                | '\u{17B5}'
+                | '\u{180B}'..='\u{180F}'
+                | '\u{200B}'..='\u{200F}'
+                | '\u{202A}'..='\u{202E}'
+                | '\u{2060}'..='\u{206F}'
+                | '\u{3164}'
+                | '\u{FE00}'..='\u{FE0F}'
+                | '\u{FEFF}'
+                | '\u{FFA0}'
+                | '\u{FFF0}'..='\u{FFF8}'
+                | '\u{E0000}'..='\u{E0FFF}'
+        )
+}
+
+/// Percent-decode `text` for display, leaving `keep` characters encoded
+///
+/// Escapes for characters hidden in display (see `is_hidden_in_display`)
+/// and runs of escapes that don't form valid UTF-8 are also left alone,
+/// so the displayed form is never ambiguous.
+fn percent_decode_for_display(text: &str, keep: &[u8]) -> String {
+    let mut display = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('%') {
+        display.push_str(&rest[..start]);
+        let run = &rest[start..];
+
+        // Decode the run of consecutive `%XX` escapes starting here
+        let mut bytes = Vec::new();
+        while let Some(hex) = run
+            .get(bytes.len() * 3..bytes.len() * 3 + 3)
+            .and_then(|escape| escape.strip_prefix('%'))
+            .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+        {
+            bytes.push(u8::from_str_radix(hex, 16).unwrap());
+        }
+        if bytes.is_empty() {
+            display.push('%');
+            rest = &run[1..];
+            continue;
+        }
+
+        let escaped = &run[..bytes.len() * 3];
+        match std::str::from_utf8(&bytes) {
+            Ok(decoded) => {
+                let mut offset = 0;
+                for c in decoded.chars() {
+                    let source = &escaped[offset * 3..(offset + c.len_utf8()) * 3];
+                    offset += c.len_utf8();
+                    let structural = c.is_ascii() && keep.contains(&(c as u8));
+                    if structural || is_hidden_in_display(c) {
+                        display.push_str(source);
+                    } else {
+                        display.push(c);
+                    }
+                }
+            }
+            Err(_) => display.push_str(escaped),
+        }
+        rest = &run[escaped.len()..];
+    }
+    display.push_str(rest);
+
+    display
+}
+
+/// Compute the human-readable form of a URL for the address bar
+///
+/// Percent-escapes in the path, query and fragment are decoded, except
+/// for characters that would change the URL's structure if shown bare
+/// (like `%2F` in a path or `%26` in a query) and characters that would
+/// be invisible or reorder the text (spaces, zero-width and bidi control
+/// characters). Punycode hosts can optionally be shown in Unicode.
+///
+/// # Arguments
+/// * `url` - Absolute URL to format
+/// * `unicode_host` - Show punycode (`xn--`) hosts in Unicode
+///
+/// # Returns
+/// The display form of the URL, or a `ValueError` if it can't be parsed
+#[pyfunction]
+#[pyo3(signature = (url, unicode_host=true))]
+fn url_display_form(url: &str, unicode_host: bool) -> PyResult<String> {
+    let parsed = Url::parse(url.trim())
+        .map_err(|e| PyValueError::new_err(format!("Invalid URL {:?}: {}", url, e)))?;
+
+    let mut display = String::with_capacity(url.len());
+    display.push_str(parsed.scheme());
+    display.push(':');
+    if let Some(host) = parsed.host_str() {
+        display.push_str("//");
+        if !parsed.username().is_empty() {
+            display.push_str(parsed.username());
+            display.push('@');
+        }
+        let (unicode, result) = idna::domain_to_unicode(host);
+        display.push_str(if unicode_host && result.is_ok() {
+            &unicode
+        } else {
+            host
+        });
+        if let Some(port) = parsed.port() {
+            display.push_str(&format!(":{}", port));
+        }
+    }
+    display.push_str(&percent_decode_for_display(parsed.path(), b"/?#%"));
+    if let Some(query) = parsed.query() {
+        display.push('?');
+        display.push_str(&percent_decode_for_display(query, b"&=+#%"));
+    }
+    if let Some(fragment) = parsed.fragment() {
+        display.push('#');
+        display.push_str(&percent_decode_for_display(fragment, b"%"));
+    }
+
+    Ok(display)
+}
+
+/// Guess whether a fetched resource is text rather than binary data
+///
+/// Looks at the first 4 KB: any NUL byte marks the data as binary, as does
+/// a share of control characters (other than tab, newlines, form feed and
+/// escape) above 10%. Bytes of 0x80 and up aren't counted, so UTF-8 and
+/// legacy 8-bit encodings both pass.
+///
+/// # Arguments
+/// * `data` - The resource body, or a prefix of it
+///
+/// # Returns
+/// `true` if the data should be shown as text
+#[pyfunction]
+fn is_probably_text(data: &[u8]) -> bool {
+    const SAMPLE_LEN: usize = 4096;
+    let sample = &data[..data.len().min(SAMPLE_LEN)];
+    if sample.is_empty() {
+        return true;
+    }
+    if sample.contains(&0) {
+        return false;
+    }
+
+    let control = sample
+        .iter()
+        .filter(|&&b| (b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0c | 0x1b)) || b == 0x7f)
+        .count();
+    control * 10 <= sample.len()
+}
+
+/// Shift the level of every heading in HTML
+///
+/// Each `<hN>` and `</hN>` tag is rewritten to level `N + offset`, clamped
+/// to 1–6; attributes and all other markup are preserved. A positive
+/// offset demotes headings (`<h1>` → `<h2>`).
+///
+/// # Arguments
+/// * `html` - HTML containing headings
+/// * `offset` - Number of levels to shift by
+///
+/// # Returns
+/// HTML with shifted heading levels
+#[pyfunction]
+fn shift_heading_levels(html: &str, offset: i8) -> String {
+    let mut result = String::with_capacity(html.len());
+
+    for token in HtmlTokenizer::new(html) {
+        let raw = token.raw();
+        let level = match &token {
+            HtmlToken::StartTag { name, .. } | HtmlToken::EndTag { name, .. } => name
+                .strip_prefix('h')
+                .and_then(|level| level.parse::<i8>().ok())
+                .filter(|level| (1..=6).contains(level)),
+            _ => None,
+        };
+        match (level, raw.find(|c: char| c.is_ascii_digit())) {
+            (Some(level), Some(digit)) => {
+                let shifted = level.saturating_add(offset).clamp(1, 6);
+                result.push_str(&raw[..digit]);
+                result.push_str(&shifted.to_string());
+                result.push_str(&raw[digit + 1..]);
+            }
+            _ => result.push_str(raw),
+        }
+    }
+
+    result
+}
+
+/// An open element tracked while scoring reader-mode candidates
+struct ContentBlock {
+    name: String,
+    start: usize,
+    text_chars: usize,
+    link_chars: usize,
+    tags: usize,
+}
+
+impl ContentBlock {
+    /// Non-link text weighted by its density relative to the markup
+    fn score(&self) -> f64 {
+        const TAG_WEIGHT: f64 = 20.0;
+        let content = self.text_chars.saturating_sub(self.link_chars) as f64;
+        content * content / (content + TAG_WEIGHT * self.tags as f64).max(1.0)
+    }
+}
+
+/// Extract the main content of a page for reader mode
+///
+/// A simplified Readability: every `<article>`, `<main>`, `<section>`,
+/// `<div>` and `<td>` is scored by its non-link text weighted by text
+/// density (text length against the number of tags it contains). Text
+/// inside navigation, headers, footers, asides, forms and scripts isn't
+/// counted, so boilerplate-heavy wrappers lose to the dense block they
+/// contain.
+///
+/// # Arguments
+/// * `html` - Page HTML
+///
+/// # Returns
+/// The HTML of the highest-scoring container, or `None` if no container
+/// holds at least a sentence or two of text
+#[pyfunction]
+fn extract_main_content(html: &str) -> Option<String> {
+    const CANDIDATES: [&str; 5] = ["article", "main", "section", "div", "td"];
+    const BOILERPLATE: [&str; 9] = [
+        "nav", "header", "footer", "aside", "form", "script", "style", "noscript", "template",
+    ];
+    const MIN_TEXT_CHARS: usize = 80;
+
+    let mut open: Vec<ContentBlock> = Vec::new();
+    let mut best: Option<(f64, usize, usize)> = None;
+    let mut pos = 0;
+
+    let mut close = |block: ContentBlock, end: usize| {
+        let content = block.text_chars.saturating_sub(block.link_chars);
+        if CANDIDATES.contains(&block.name.as_str()) && content >= MIN_TEXT_CHARS {
+            let score = block.score();
+            if best.is_none_or(|(best_score, _, _)| score > best_score) {
+                best = Some((score, block.start, end));
+            }
+        }
+    };
+
+    for token in HtmlTokenizer::new(html) {
+        let start = pos;
+        pos += token.raw().len();
+        match token {
+            HtmlToken::Text(text) => {
+                if open.iter().any(|b| BOILERPLATE.contains(&b.name.as_str())) {
+                    continue;
+                }
+                let chars = text.chars().filter(|c| !c.is_whitespace()).count();
+                let in_link = open.iter().any(|b| b.name == "a");
+                for block in &mut open {
+                    block.text_chars += chars;
+                    if in_link {
+                        block.link_chars += chars;
+                    }
+                }
+            }
+            HtmlToken::StartTag {
+                name, self_closing, ..
+            } => {
+                for block in &mut open {
+                    block.tags += 1;
+                }
+                if !self_closing && !VOID_ELEMENTS.contains(&name.as_str()) {
+                    open.push(ContentBlock {
+                        name,
+                        start,
+                        text_chars: 0,
+                        link_chars: 0,
+                        tags: 0,
+                    });
+                }
+            }
+            HtmlToken::EndTag { name, .. } => {
+                // Close the matching element, and any left unclosed inside it
+                if let Some(index) = open.iter().rposition(|b| b.name == name) {
+                    while open.len() > index + 1 {
+                        let block = open.pop().unwrap();
+                        close(block, start);
+                    }
+                    let block = open.pop().unwrap();
+                    close(block, pos);
+                }
+            }
+            HtmlToken::Other(_) => {}
+        }
+    }
+    while let Some(block) = open.pop() {
+        close(block, html.len());
+    }
+
+    best.map(|(_, start, end)| html[start..end].to_string())
+}
+
+/// Find highlight ranges for several keywords at once
+///
+/// All case-insensitive occurrences of every keyword are collected, then
+/// overlaps are resolved in favour of the longer match (the earlier one
+/// on ties). Keywords are matched literally.
+///
+/// # Arguments
+/// * `text` - Text to search
+/// * `keywords` - Keywords to highlight
+///
+/// # Returns
+/// Non-overlapping `(start, end, keyword)` byte ranges in text order
+#[pyfunction]
+fn multi_keyword_spans(text: &str, keywords: Vec<String>) -> Vec<(usize, usize, String)> {
+    let mut candidates = Vec::new();
+    for (index, keyword) in keywords.iter().enumerate() {
+        if keyword.is_empty() {
+            continue;
+        }
+        let Ok(re) = Regex::new(&format!("(?i){}", regex::escape(keyword))) else {
+            continue;
+        };
+        // Restart one character after each match so overlapping hits are found
+        let mut from = 0;
+        while let Some(m) = re.find_at(text, from) {
+            candidates.push((m.start(), m.end(), index));
+            from = m.start() + text[m.start()..].chars().next().map_or(1, char::len_utf8);
+        }
+    }
+
+    candidates.sort_by(|a, b| (b.1 - b.0).cmp(&(a.1 - a.0)).then(a.0.cmp(&b.0)));
+    // Accepted spans never overlap, so only the last one starting before
+    // `end` can collide with a candidate
+    let mut spans: BTreeMap<usize, (usize, usize)> = BTreeMap::new();
+    for (start, end, index) in candidates {
+        if spans
+            .range(..end)
+            .next_back()
+            .is_none_or(|(_, &(e, _))| e <= start)
+        {
+            spans.insert(start, (end, index));
+        }
+    }
+
+    spans
+        .into_iter()
+        .map(|(start, (end, index))| (start, end, keywords[index].clone()))
+        .collect()
+}
+
+/// Join hard-wrapped lines back into single-line paragraphs
+///
+/// Consecutive non-blank lines are joined with single spaces. Blank
+/// lines between paragraphs are kept, each list item starts a new line
+/// (wrapped continuation lines are joined onto it), and headings, table
+/// rows, blockquotes, indented and fenced code blocks are copied verbatim.
+///
+/// # Arguments
+/// * `text` - Plain text or markdown with hard-wrapped prose
+///
+/// # Returns
+/// The text with paragraphs unwrapped
+#[pyfunction]
+fn unwrap_paragraphs(text: &str) -> String {
+    let mut output: Vec<String> = Vec::new();
+    let mut paragraph: Option<String> = None;
+    let mut in_list_item = false;
+    let mut fence: Option<&str> = None;
+
+    for line in text.lines() {
+        if let Some(open) = fence {
+            if closes_fence(line, open) {
+                fence = None;
+            }
+            output.push(line.to_string());
+            continue;
+        }
+
+        let trimmed = line.trim();
+        let is_list_item = LIST_ITEM_RE.is_match(line);
+        // Indented lines only continue a list item; elsewhere they're code
+        let indented = line.starts_with([' ', '\t']) && !(in_list_item && paragraph.is_some());
+        let standalone = trimmed.is_empty()
+            || trimmed.starts_with('#')
+            || trimmed.starts_with('|')
+            || trimmed.starts_with('>')
+            || (indented && !is_list_item)
+            || parse_fence_line(line).is_some();
+        if standalone {
+            output.extend(paragraph.take());
+            fence = parse_fence_line(line).map(|(open, _)| open);
+            output.push(line.trim_end().to_string());
+            continue;
+        }
+
+        match paragraph.as_mut() {
+            Some(current) if !is_list_item => {
+                current.push(' ');
+                current.push_str(trimmed);
+            }
+            _ => {
+                output.extend(paragraph.take());
+                paragraph = Some(line.trim_end().to_string());
+                in_list_item = is_list_item;
+            }
+        }
+    }
+    output.extend(paragraph);
+
+    output.join("\n")
+}
+
+/// Compute a short, stable hash of text for use as an element id
+///
+/// The first 128 bits of the text's SHA-256 are written in base62
+/// (`0-9A-Za-z`), least significant digit first so that every prefix is
+/// evenly distributed. Eight characters give about 47 bits, which makes
+/// collisions between the headings of a page vanishingly unlikely.
+///
+/// # Arguments
+/// * `text` - Text to hash
+/// * `length` - Number of characters to return, at most 22
+///
+/// # Returns
+/// The first `length` base62 characters of the hash
+#[pyfunction]
+fn short_hash(text: &str, length: usize) -> String {
+    const ALPHABET: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+    const MAX_LENGTH: usize = 22;
+
+    let digest = Sha256::digest(text.as_bytes());
+    let mut value = u128::from_be_bytes(digest[..16].try_into().unwrap());
+    let mut hash = String::with_capacity(length.min(MAX_LENGTH));
+    for _ in 0..length.min(MAX_LENGTH) {
+        hash.push(ALPHABET[(value % 62) as usize] as char);
+        value /= 62;
+    }
+    hash
+}
+
+/// Normalize the value of an HTML `class` attribute
+///
+/// Splits on whitespace and drops repeated classes, keeping the first
+/// occurrence of each so merged class lists stay in a predictable order.
+///
+/// # Arguments
+/// * `classes` - Whitespace-separated class names
+///
+/// # Returns
+/// The unique class names joined by single spaces
+#[pyfunction]
+fn normalize_class_list(classes: &str) -> String {
+    let mut seen = HashSet::new();
+    classes
+        .split_whitespace()
+        .filter(|class| seen.insert(*class))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Split text into chunks of at most `max_bytes` UTF-8 bytes
+///
+/// Each chunk ends at the last whitespace that fits, and the whitespace
+/// at the break is dropped. A word longer than `max_bytes` on its own is
+/// force-split at the last character boundary that fits, so multibyte
+/// characters are never cut.
+///
+/// # Arguments
+/// * `text` - Text to split
+/// * `max_bytes` - Maximum size of each chunk in bytes
+///
+/// # Returns
+/// The chunks in order, or a `ValueError` if `max_bytes` is smaller than a
+/// single character of the text
+#[pyfunction]
+fn split_by_byte_limit(text: &str, max_bytes: usize) -> PyResult<Vec<String>> {
+    let mut chunks = Vec::new();
+    let mut rest = text.trim_start();
+
+    while rest.len() > max_bytes {
+        let mut limit = max_bytes;
+        while !rest.is_char_boundary(limit) {
+            limit -= 1;
+        }
+        // Prefer breaking at whitespace, including whitespace just past the limit
+        let window = rest.get(..limit + 1).unwrap_or(&rest[..limit]);
+        let (chunk, next) = match window.rfind(char::is_whitespace) {
+            Some(i) if i > 0 => (&rest[..i], &rest[i..]),
+            _ if limit > 0 => (&rest[..limit], &rest[limit..]),
+            _ => {
+                return Err(PyValueError::new_err(format!(
+                    "max_bytes ({}) is smaller than a single character",
+                    max_bytes
+                )))
+            }
+        };
+        chunks.push(chunk.trim_end().to_string());
+        rest = next.trim_start();
+    }
+    if !rest.is_empty() {
+        chunks.push(rest.to_string());
+    }
+
+    Ok(chunks)
+}
+
+/// Render the status widget shown while an agent is working
+///
+/// Produces a `<div class="status" role="status">` holding an optional
+/// spinner, the escaped label and, when `percent` is given, a
+/// `<progress>` bar. Styling is left to the page's stylesheet.
+///
+/// # Arguments
+/// * `label` - Status text, e.g. "Fetching page"
+/// * `percent` - Completion percentage, capped at 100; None for an
+///   indeterminate status
+/// * `spinner` - Whether to include a spinner element
+///
+/// # Returns
+/// HTML snippet for the status widget
+#[pyfunction]
+#[pyo3(signature = (label, percent=None, spinner=false))]
+fn render_status_html(label: &str, percent: Option<u8>, spinner: bool) -> String {
+    let mut html = String::from(r#"<div class="status" role="status">"#);
+    if spinner {
+        html.push_str(r#"<span class="status-spinner" aria-hidden="true"></span>"#);
+    }
+    html.push_str(&format!(
+        r#"<span class="status-label">{}</span>"#,
+        escape_html_text(label)
+    ));
+    if let Some(percent) = percent {
+        let percent = percent.min(100);
+        html.push_str(&format!(
+            r#"<progress class="status-progress" max="100" value="{0}">{0}%</progress>"#,
+            percent
+        ));
+    }
+    html.push_str("</div>");
+    html
+}
+
+/// Compute a sort key ordering conversation entries deterministically
+///
+/// Entries sort by timestamp, then by role (system, user, assistant,
+/// tool, then any other role), then by id. The timestamp is offset so
+/// negative values sort correctly and zero-padded, making plain string
+/// comparison of keys match that order.
+///
+/// # Arguments
+/// * `timestamp` - Entry timestamp
+/// * `role` - Entry role, compared case-insensitively
+/// * `id` - Entry id, used as a tie-breaker
+///
+/// # Returns
+/// Key of the form `<20-digit timestamp>:<role rank>:<id>`
+#[pyfunction]
+fn sort_key(timestamp: i64, role: &str, id: &str) -> String {
+    let rank = match role.to_ascii_lowercase().as_str() {
+        "system" => 0,
+        "user" => 1,
+        "assistant" => 2,
+        "tool" => 3,
+        _ => 9,
+    };
+    let ordered = (timestamp as u64) ^ (1 << 63);
+    format!("{:020}:{}:{}", ordered, rank, id)
+}
+
+/// List the alt text of every image for accessibility auditing
+///
+/// An empty `alt=""` marks an image as decorative, so it is reported as
+/// `Some("")` rather than as missing.
+///
+/// # Arguments
+/// * `html` - Page HTML
+///
+/// # Returns
+/// List of (src, alt) tuples in document order, with `src` empty when the
+/// tag has none and `alt` None when the attribute is absent
+#[pyfunction]
+fn audit_image_alts(html: &str) -> Vec<(String, Option<String>)> {
+    tag_attributes(html, "img")
+        .map(|attrs| {
+            let src = find_attribute(&attrs, "src")
+                .unwrap_or("")
+                .trim()
+                .to_string();
+            let alt = find_attribute(&attrs, "alt").map(|alt| alt.trim().to_string());
+            (src, alt)
+        })
+        .collect()
+}
+
+/// Count characters, words, lines and bytes in a single pass
+///
+/// Words are runs of non-whitespace characters. Lines are the
+/// `\n`-terminated lines plus a final unterminated one, so `"a\nb"` and
+/// `"a\nb\n"` both have two lines and the empty string has none.
+///
+/// # Arguments
+/// * `text` - Text to measure
+///
+/// # Returns
+/// Tuple of (chars, words, lines, bytes)
+#[pyfunction]
+fn text_stats(text: &str) -> (usize, usize, usize, usize) {
+    let (mut chars, mut words, mut newlines) = (0, 0, 0);
+    let mut in_word = false;
+
+    for c in text.chars() {
+        chars += 1;
+        if c == '\n' {
+            newlines += 1;
+        }
+        if c.is_whitespace() {
+            in_word = false;
+        } else if !in_word {
+            in_word = true;
+            words += 1;
+        }
+    }
+
+    let lines = newlines + usize::from(!text.is_empty() && !text.ends_with('\n'));
+    (chars, words, lines, text.len())
+}
+
+/// Split a code fence info string into its language and attributes
+///
+/// For ```` ```python title="main.py" hl=1-3 ```` the language is `python`
+/// and the attributes are `title` and `hl`. Values may be bare, single-
+/// or double-quoted; tokens without `=` after the language are ignored.
+///
+/// # Arguments
+/// * `info` - Info string following the opening fence
+///
+/// # Returns
+/// Tuple of (language, list of (key, value) attributes), with an empty
+/// language when the info string starts with an attribute
+#[pyfunction]
+fn parse_fence_info(info: &str) -> (String, Vec<(String, String)>) {
+    let info = info.trim();
+    let first_end = info.find(char::is_whitespace).unwrap_or(info.len());
+    let (language, rest) = if info[..first_end].contains('=') {
+        ("", info)
+    } else {
+        info.split_at(first_end)
+    };
+
+    let attributes = ATTR_RE
+        .captures_iter(rest)
+        .filter_map(|cap| {
+            let value = cap.get(2).or_else(|| cap.get(3)).or_else(|| cap.get(4))?;
+            Some((cap[1].to_string(), value.as_str().to_string()))
+        })
+        .collect();
+
+    (language.to_string(), attributes)
+}
+
+/// Pick a stable swatch color for an agent or user name
+///
+/// The name is hashed (after trimming and lowercasing, so `Alice` and
+/// `alice ` match) into a curated palette of distinct hues, all dark
+/// enough for white text to meet WCAG AA contrast.
+///
+/// # Arguments
+/// * `name` - Identity name
+///
+/// # Returns
+/// A `#rrggbb` color
+#[pyfunction]
+fn identity_color(name: &str) -> String {
+    const PALETTE: [&str; 16] = [
+        "#b91c1c", "#c2410c", "#a16207", "#4d7c0f", "#15803d", "#047857", "#0f766e", "#0e7490",
+        "#0369a1", "#1d4ed8", "#4338ca", "#6d28d9", "#7e22ce", "#a21caf", "#be185d", "#be123c",
+    ];
+    let key = name.trim().to_lowercase();
+    let index = mix64(fnv1a64(key.as_bytes())) % PALETTE.len() as u64;
+    PALETTE[index as usize].to_string()
+}
+
+/// Check that a JSON object has all the required top-level keys
+///
+/// # Arguments
+/// * `json` - JSON text, e.g. an agent protocol message
+/// * `required` - Keys that must be present
+///
+/// # Returns
+/// True if every required key is present, or a `ValueError` if the text
+/// isn't valid JSON or its root isn't an object
+#[pyfunction]
+fn json_has_keys(json: &str, required: Vec<String>) -> PyResult<bool> {
+    let value: serde_json::Value = serde_json::from_str(json)
+        .map_err(|e| PyValueError::new_err(format!("Invalid JSON: {}", e)))?;
+    let object = value
+        .as_object()
+        .ok_or_else(|| PyValueError::new_err("JSON root is not an object"))?;
+    Ok(required.iter().all(|key| object.contains_key(key)))
+}
+
+/// Extract the first code block together with its expected output
+///
+/// Matches a fenced code block immediately followed, with at most one
+/// blank line in between, by a second fence whose language is `output`
+/// or `text`.
+///
+/// # Arguments
+/// * `text` - Markdown text
+///
+/// # Returns
+/// Tuple of (code, output), or None if the first code block has no
+/// output fence after it
+#[pyfunction]
+fn extract_code_and_output(text: &str) -> Option<(String, String)> {
+    let blocks = scan_fenced_blocks(text);
+    let [code, output, ..] = blocks.as_slice() else {
+        return None;
+    };
+
+    let gap = output.start_line.checked_sub(code.end_line + 1)?;
+    let gap_is_blank = text
+        .lines()
+        .skip(code.end_line + 1)
+        .take(gap)
+        .all(|line| line.trim().is_empty());
+    let language = parse_fence_info(&output.info).0.to_ascii_lowercase();
+
+    (gap <= 1 && gap_is_blank && matches!(language.as_str(), "output" | "text"))
+        .then(|| (code.code.clone(), output.code.clone()))
+}
+
+/// Split a streaming buffer into decoded text and an incomplete tail
+///
+/// A multibyte character cut off at the end of the buffer is returned
+/// as raw bytes to prepend to the next chunk instead of being decoded as
+/// a replacement character. Invalid sequences elsewhere are still
+/// replaced with U+FFFD.
+///
+/// # Arguments
+/// * `data` - Bytes received so far
+///
+/// # Returns
+/// Tuple of (decoded text, trailing bytes of an incomplete character)
+#[pyfunction]
+fn split_complete_utf8(data: &[u8]) -> (String, Vec<u8>) {
+    let mut split = data.len();
+    for back in 1..=data.len().min(3) {
+        let byte = data[data.len() - back];
+        // Skip continuation bytes until the lead byte of the last character
+        if byte & 0xC0 != 0x80 {
+            let width = match byte {
+                0xC0..=0xDF => 2,
+                0xE0..=0xEF => 3,
+                0xF0..=0xF7 => 4,
+                _ => 1,
+            };
+            if width > back {
+                split = data.len() - back;
+            }
+            break;
+        }
+    }
+
+    let (complete, tail) = data.split_at(split);
+    (
+        String::from_utf8_lossy(complete).into_owned(),
+        tail.to_vec(),
+    )
+}
+
+/// Find the longest substring that occurs at least twice in text
+///
+/// Builds a suffix array by prefix doubling and scans the longest common
+/// prefixes of adjacent suffixes (Kasai's algorithm), which stays fast on
+/// whole pages. The two occurrences may overlap. Lengths are counted in
+/// characters.
+///
+/// # Arguments
+/// * `text` - Text to search, e.g. the concatenated text of several pages
+/// * `min_len` - Minimum length of the substring, at least 1
+///
+/// # Returns
+/// The longest repeated substring, or None if none has `min_len` characters
+#[pyfunction]
+fn longest_repeated_substring(text: &str, min_len: usize) -> Option<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let n = chars.len();
+    if n < 2 {
+        return None;
+    }
+
+    let mut suffixes: Vec<usize> = (0..n).collect();
+    let mut rank: Vec<usize> = chars.iter().map(|&c| c as usize).collect();
+    let mut next_rank = vec![0; n];
+    let mut k = 1;
+    loop {
+        let key = |i: usize| (rank[i], rank.get(i + k).map_or(0, |r| r + 1));
+        suffixes.sort_unstable_by_key(|&i| key(i));
+        next_rank[suffixes[0]] = 0;
+        for w in 1..n {
+            let changed = key(suffixes[w - 1]) != key(suffixes[w]);
+            next_rank[suffixes[w]] = next_rank[suffixes[w - 1]] + usize::from(changed);
+        }
+        rank.copy_from_slice(&next_rank);
+        if rank[suffixes[n - 1]] == n - 1 || k >= n {
+            break;
+        }
+        k *= 2;
+    }
+
+    // `rank` now holds each suffix's position in `suffixes`
+    let (mut best_len, mut best_start) = (0, 0);
+    let mut common = 0;
+    for i in 0..n {
+        if rank[i] == 0 {
+            common = 0;
+            continue;
+        }
+        let j = suffixes[rank[i] - 1];
+        while i + common < n && j + common < n && chars[i + common] == chars[j + common] {
+            common += 1;
+        }
+        if common > best_len {
+            (best_len, best_start) = (common, i);
+        }
+        common = common.saturating_sub(1);
+    }
+
+    (best_len >= min_len.max(1)).then(|| chars[best_start..best_start + best_len].iter().collect())
+}
+
+/// Find phone numbers in text for click-to-call links
+///
+/// Digit sequences that look like phone numbers are parsed with the
+/// libphonenumber metadata; numbers without a `+` country code are read
+/// as belonging to `default_region`, and candidates that aren't valid
+/// numbers are skipped.
+///
+/// # Arguments
+/// * `text` - Text to search
+/// * `default_region` - ISO 3166-1 alpha-2 region code, e.g. "US"
+///
+/// # Returns
+/// Unique numbers in E.164 format (`+14155552671`), in order of appearance
+#[pyfunction]
+fn extract_phone_numbers(text: &str, default_region: &str) -> Vec<String> {
+    let region = default_region
+        .trim()
+        .to_ascii_uppercase()
+        .parse::<phonenumber::country::Id>()
+        .ok();
+    let mut seen = HashSet::new();
+
+    PHONE_CANDIDATE_RE
+        .find_iter(text)
+        .filter_map(|m| phonenumber::parse(region, m.as_str()).ok())
+        .filter(phonenumber::is_valid)
+        .map(|number| number.format().mode(phonenumber::Mode::E164).to_string())
+        .filter(|number| seen.insert(number.clone()))
+        .collect()
+}
+
+/// Filter an inline `style` attribute down to safe declarations
+///
+/// Keeps only `prop: value` declarations whose property is in
+/// `allowed_props` and whose value can't load resources or run script:
+/// values containing `url(`, `image(`, `image-set(`, `expression(` or
+/// `javascript:` are dropped, as are values with backslash escapes or
+/// comments that could hide them. Values containing `"`, `<`, `>` or `&`
+/// are dropped too, so the result is safe inside a double-quoted `style`
+/// attribute, where the HTML parser would decode character references
+/// like `&#x3a;` before the CSS parser sees them. Semicolons inside quotes or parentheses don't split
+/// declarations.
+///
+/// # Arguments
+/// * `style` - Inline style text
+/// * `allowed_props` - Allowed property names, compared case-insensitively
+///
+/// # Returns
+/// The safe declarations serialized as `prop: value; prop: value`
+#[pyfunction]
+fn sanitize_inline_style(style: &str, allowed_props: HashSet<String>) -> String {
+    const BLOCKED: [&str; 5] = ["url(", "image(", "image-set(", "expression(", "javascript:"];
+    let allowed: HashSet<String> = allowed_props
+        .iter()
+        .map(|p| p.to_ascii_lowercase())
+        .collect();
+
+    let mut declarations = Vec::new();
+    let (mut start, mut depth, mut quote) = (0, 0usize, None);
+    for (i, c) in style.char_indices().chain([(style.len(), ';')]) {
+        match (quote, c) {
+            (Some(q), _) if q == c => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => depth = depth.saturating_sub(1),
+            (None, ';') if depth == 0 || i == style.len() => {
+                declarations.push(&style[start..i]);
+                start = (i + 1).min(style.len());
+            }
+            _ => {}
+        }
+    }
+
+    declarations
+        .into_iter()
+        .filter_map(|declaration| {
+            let (prop, value) = declaration.split_once(':')?;
+            let prop = prop.trim().to_ascii_lowercase();
+            let value = value.trim();
+            let compact: String = value
+                .chars()
+                .filter(|c| !c.is_whitespace())
+                .collect::<String>()
+                .to_ascii_lowercase();
+            let unsafe_value = value.contains(['\\', '"', '<', '>', '&'])
+                || value.contains("/*")
+                || BLOCKED.iter().any(|pattern| compact.contains(pattern));
+            (allowed.contains(&prop) && !value.is_empty() && !unsafe_value)
+                .then(|| format!("{}: {}", prop, value))
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Rewrite HTML into a canonical form for comparing renders
+///
+/// Tag and attribute names are lowercased, attributes are sorted by name
+/// (keeping the first of any duplicates) and re-quoted with their values
+/// consistently escaped, void elements lose their `/`, and whitespace is
+/// collapsed as in `collapse_html_whitespace`. Text, comments and the
+/// contents of `<pre>` and friends are otherwise left as-is.
+///
+/// # Arguments
+/// * `html` - HTML to canonicalize
+///
+/// # Returns
+/// Canonical HTML; semantically equal inputs produce identical output
+#[pyfunction]
+fn canonicalize_html(html: &str) -> String {
+    let mut result = String::with_capacity(html.len());
+
+    for token in HtmlTokenizer::new(html) {
+        match token {
+            HtmlToken::StartTag {
+                name,
+                attrs,
+                self_closing,
+                ..
+            } => {
+                let mut attrs = parse_attributes(attrs);
+                attrs.sort_by(|a, b| a.0.cmp(&b.0));
+                attrs.dedup_by(|later, first| later.0 == first.0);
+                result.push('<');
+                result.push_str(&name);
+                for (key, value) in attrs {
+                    result.push_str(&format!(" {}=\"{}\"", key, escape_html_text(&value)));
+                }
+                if self_closing && !VOID_ELEMENTS.contains(&name.as_str()) {
+                    result.push('/');
+                }
+                result.push('>');
+            }
+            HtmlToken::EndTag { name, .. } => result.push_str(&format!("</{}>", name)),
+            token => result.push_str(token.raw()),
+        }
+    }
+
+    collapse_html_whitespace(&result)
+}
+
+/// Extract the spans of text enclosed in double quotes
+///
+/// Straight quotes pair with each other and curly quotes `“` with `”`.
+/// Single quotes and apostrophes inside a span are kept as part of it,
+/// and an opening quote with no closing partner is ignored.
+///
+/// # Arguments
+/// * `text` - Text to search, e.g. a response citing sources
+///
+/// # Returns
+/// The non-empty quoted spans without their quotes, in order
+#[pyfunction]
+fn extract_quotes(text: &str) -> Vec<String> {
+    let mut quotes = Vec::new();
+    let mut rest = text;
+
+    while let Some(open) = rest.find(['"', '“']) {
+        let closing = if rest[open..].starts_with('"') {
+            '"'
+        } else {
+            '”'
+        };
+        let body_start = open + rest[open..].chars().next().map_or(1, char::len_utf8);
+        let Some(len) = rest[body_start..].find(closing) else {
+            // Unmatched: skip this quote and look for later pairs
+            rest = &rest[body_start..];
+            continue;
+        };
+        let span = rest[body_start..body_start + len].trim();
+        if !span.is_empty() {
+            quotes.push(span.to_string());
+        }
+        rest = &rest[body_start + len + closing.len_utf8()..];
+    }
+
+    quotes
+}
+
+/// Map a byte offset in the plain text of HTML back onto the HTML
+///
+/// The plain text is the HTML with every tag, comment and `<script>` or
+/// `<style>` element removed and character references decoded (as
+/// `decode_html_entities` does), with whitespace left as-is. An offset
+/// inside a decoded reference maps to the `&` that starts it. An offset
+/// at the boundary between two text runs maps to the start of the later
+/// run, while the offset just past the end of the plain text maps to the
+/// end of the last run.
+///
+/// # Arguments
+/// * `html` - Original HTML
+/// * `plaintext_offset` - Byte offset into the plain text
+///
+/// # Returns
+/// Corresponding byte offset in `html`, or None if the offset is past
+/// the end of the plain text
+#[pyfunction]
+fn plaintext_to_html_offset(html: &str, plaintext_offset: usize) -> Option<usize> {
+    let mut html_pos = 0;
+    let mut text_pos = 0;
+    let mut text_end = None;
+    let mut in_raw_text = false;
+
+    for token in HtmlTokenizer::new(html) {
+        let raw = token.raw();
+        match token {
+            HtmlToken::StartTag {
+                name, self_closing, ..
+            } if name == "script" || name == "style" => in_raw_text = !self_closing,
+            HtmlToken::EndTag { name, .. } if name == "script" || name == "style" => {
+                in_raw_text = false
+            }
+            HtmlToken::Text(text) if !in_raw_text => {
+                let mut last = 0;
+                let references = CHAR_REF_RE
+                    .find_iter(text)
+                    .map(|m| (m.start(), m.end(), decode_html_entities(m.as_str())))
+                    .filter(|(start, end, decoded)| *decoded != text[*start..*end]);
+                for (start, end, decoded) in references {
+                    let literal = start - last;
+                    if plaintext_offset < text_pos + literal {
+                        return Some(html_pos + last + plaintext_offset - text_pos);
+                    }
+                    text_pos += literal;
+                    if plaintext_offset < text_pos + decoded.len() {
+                        return Some(html_pos + start);
+                    }
+                    text_pos += decoded.len();
+                    last = end;
+                }
+                let literal = text.len() - last;
+                if plaintext_offset < text_pos + literal {
+                    return Some(html_pos + last + plaintext_offset - text_pos);
+                }
+                text_pos += literal;
+                text_end = Some(html_pos + text.len());
+            }
+            _ => {}
+        }
+        html_pos += raw.len();
+    }
+
+    (plaintext_offset == text_pos).then(|| text_end.unwrap_or(0))
+}
+
+/// Split a CSS declaration block on the `;`s that end declarations
+///
+/// Semicolons inside quoted strings or parentheses, like the one in
+/// `url("a;b")`, are part of the value.
+fn split_css_declarations(block: &str) -> Vec<&str> {
+    let mut declarations = Vec::new();
+    let mut start = 0;
+    let mut depth = 0usize;
+    let mut quote: Option<char> = None;
+    let mut chars = block.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (_, '\\') => {
+                chars.next();
+            }
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => depth = depth.saturating_sub(1),
+            (None, ';') if depth == 0 => {
+                declarations.push(&block[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    declarations.push(&block[start..]);
+    declarations
+}
+
+/// Extract the custom properties declared on `:root` in a stylesheet
+///
+/// Reads every rule whose selector list includes `:root` (also inside
+/// `@media` blocks). Comments are removed first, so commented-out
+/// declarations are excluded; later declarations of the same name are
+/// kept as separate pairs, in order.
+///
+/// # Arguments
+/// * `css` - Stylesheet text
+///
+/// # Returns
+/// List of (name, value) tuples, with names including the leading `--`
+#[pyfunction]
+fn extract_css_variables(css: &str) -> Vec<(String, String)> {
+    let css = CSS_COMMENT_RE.replace_all(css, " ");
+    let mut variables = Vec::new();
+
+    for cap in CSS_RULE_RE.captures_iter(&css) {
+        let is_root = cap[1]
+            .split(',')
+            .any(|selector| selector.trim().eq_ignore_ascii_case(":root"));
+        if !is_root {
+            continue;
+        }
+        for declaration in split_css_declarations(&cap[2]) {
+            let Some((name, value)) = declaration.split_once(':') else {
+                continue;
+            };
+            let (name, value) = (name.trim(), value.trim());
+            if name.len() > 2 && name.starts_with("--") && !value.is_empty() {
+                variables.push((name.to_string(), value.to_string()));
+            }
+        }
+    }
+
+    variables
+}
+
+/// Collect every class name used in an HTML document
+///
+/// Lets the page saver drop CSS rules for classes that never appear.
+///
+/// # Arguments
+/// * `html` - Page HTML
+///
+/// # Returns
+/// Set of class names from all `class` attributes
+#[pyfunction]
+fn collect_used_classes(html: &str) -> HashSet<String> {
+    let mut classes = HashSet::new();
+
+    for token in HtmlTokenizer::new(html) {
+        if let HtmlToken::StartTag { attrs, .. } = token {
+            let attrs = parse_attributes(attrs);
+            if let Some(class) = find_attribute(&attrs, "class") {
+                classes.extend(class.split_whitespace().map(str::to_string));
+            }
+        }
+    }
+
+    classes
+}
+
+/// Separate a leading `<think>` block from the answer of a response
+///
+/// Recognizes `<think>...</think>` and `<thinking>...</thinking>` at the
+/// start of the text (tags are matched case-insensitively). A block that
+/// is never closed, as while the reasoning is still streaming, takes the
+/// rest of the text.
+///
+/// # Arguments
+/// * `text` - Model response
+///
+/// # Returns
+/// Tuple of (thinking, answer), both trimmed, with thinking None when
+/// the response doesn't start with a think block
+#[pyfunction]
+fn split_thinking(text: &str) -> (Option<String>, String) {
+    let trimmed = text.trim_start();
+    let lower = trimmed.to_ascii_lowercase();
+
+    for tag in ["think", "thinking"] {
+        let open = format!("<{}>", tag);
+        if !lower.starts_with(&open) {
+            continue;
+        }
+        let body = &trimmed[open.len()..];
+        let close = format!("</{}>", tag);
+        return match lower[open.len()..].find(&close) {
+            Some(end) => (
+                Some(body[..end].trim().to_string()),
+                body[end + close.len()..].trim().to_string(),
+            ),
+            None => (Some(body.trim().to_string()), String::new()),
+        };
+    }
+
+    (None, text.trim().to_string())
+}
+
+/// Python module definition
+#[pymodule]
+fn minimal_browser_native(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(extract_url_from_text, m)?)?;
+    m.add_function(wrap_pyfunction!(find_all_patterns, m)?)?;
+    m.add_function(wrap_pyfunction!(fast_string_contains, m)?)?;
+    m.add_function(wrap_pyfunction!(base64_encode_optimized, m)?)?;
+    m.add_function(wrap_pyfunction!(markdown_to_html, m)?)?;
+    m.add_function(wrap_pyfunction!(markdown_to_html_batch, m)?)?;
+    m.add_function(wrap_pyfunction!(common_url_prefix, m)?)?;
+    m.add_function(wrap_pyfunction!(extract_toc, m)?)?;
+    m.add_function(wrap_pyfunction!(add_heading_anchors, m)?)?;
+    m.add_function(wrap_pyfunction!(collapse_html_whitespace, m)?)?;
+    m.add_function(wrap_pyfunction!(css_escape, m)?)?;
+    m.add_function(wrap_pyfunction!(canonicalize_base64, m)?)?;
+    m.add_function(wrap_pyfunction!(extract_numbers, m)?)?;
+    m.add_function(wrap_pyfunction!(locate_code_blocks, m)?)?;
+    m.add_function(wrap_pyfunction!(expand_template, m)?)?;
+    m.add_function(wrap_pyfunction!(detect_charset, m)?)?;
+    m.add_function(wrap_pyfunction!(decode_bytes, m)?)?;
+    m.add_function(wrap_pyfunction!(extract_meta_description, m)?)?;
+    m.add_function(wrap_pyfunction!(simhash, m)?)?;
+    m.add_function(wrap_pyfunction!(hamming_distance, m)?)?;
+    m.add_function(wrap_pyfunction!(markdown_to_plaintext, m)?)?;
+    m.add_function(wrap_pyfunction!(is_valid_email, m)?)?;
+    m.add_function(wrap_pyfunction!(split_args, m)?)?;
+    m.add_function(wrap_pyfunction!(display_width, m)?)?;
+    m.add_function(wrap_pyfunction!(wrap_text, m)?)?;
+    m.add_function(wrap_pyfunction!(char_to_byte_offset, m)?)?;
+    m.add_function(wrap_pyfunction!(byte_to_char_offset, m)?)?;
+    m.add_function(wrap_pyfunction!(wrap_matches, m)?)?;
+    m.add_function(wrap_pyfunction!(parse_markdown_table, m)?)?;
+    m.add_function(wrap_pyfunction!(is_safe_navigation_url, m)?)?;
+    m.add_function(wrap_pyfunction!(extract_all_groups, m)?)?;
+    m.add_function(wrap_pyfunction!(word_diff, m)?)?;
+    m.add_function(wrap_pyfunction!(is_balanced_html, m)?)?;
+    m.add_function(wrap_pyfunction!(extract_favicon, m)?)?;
+    m.add_function(wrap_pyfunction!(resolve_url, m)?)?;
+    m.add_function(wrap_pyfunction!(extract_links, m)?)?;
+    m.add_function(wrap_pyfunction!(list_patch, m)?)?;
+    m.add_function(wrap_pyfunction!(entry_id, m)?)?;
+    m.add_function(wrap_pyfunction!(detect_repetition, m)?)?;
+    m.add_function(wrap_pyfunction!(detect_language, m)?)?;
+    m.add_function(wrap_pyfunction!(clean_tracking_params, m)?)?;
+    m.add_function(wrap_pyfunction!(group_match_values, m)?)?;
+    m.add_function(wrap_pyfunction!(flesch_reading_ease, m)?)?;
+    m.add_function(wrap_pyfunction!(build_attributes, m)?)?;
+    m.add_function(wrap_pyfunction!(extract_jsonld, m)?)?;
+    m.add_function(wrap_pyfunction!(format_bytes, m)?)?;
+    m.add_function(wrap_pyfunction!(url_cache_key, m)?)?;
+    m.add_function(wrap_pyfunction!(title_from_text, m)?)?;
+    m.add_function(wrap_pyfunction!(normalize_color, m)?)?;
+    m.add_function(wrap_pyfunction!(extract_stylesheets, m)?)?;
+    m.add_function(wrap_pyfunction!(merge_keyword_sets, m)?)?;
+    m.add_function(wrap_pyfunction!(analyze_response, m)?)?;
+    m.add_function(wrap_pyfunction!(markdown_escape, m)?)?;
+    m.add_function(wrap_pyfunction!(seeded_shuffle, m)?)?;
+    m.add_function(wrap_pyfunction!(trim_to_budget, m)?)?;
+    m.add_function(wrap_pyfunction!(dominant_color, m)?)?;
+    m.add_function(wrap_pyfunction!(parse_headers, m)?)?;
+    m.add_function(wrap_pyfunction!(same_origin, m)?)?;
+    m.add_function(wrap_pyfunction!(decode_html_entities, m)?)?;
+    m.add_function(wrap_pyfunction!(svg_to_data_url, m)?)?;
+    m.add_function(wrap_pyfunction!(split_sections, m)?)?;
+    m.add_function(wrap_pyfunction!(url_display_form, m)?)?;
+    m.add_function(wrap_pyfunction!(is_probably_text, m)?)?;
+    m.add_function(wrap_pyfunction!(shift_heading_levels, m)?)?;
+    m.add_function(wrap_pyfunction!(extract_main_content, m)?)?;
+    m.add_function(wrap_pyfunction!(multi_keyword_spans, m)?)?;
+    m.add_function(wrap_pyfunction!(line_diff_ignore_whitespace, m)?)?;
+    m.add_function(wrap_pyfunction!(extract_canonical_url, m)?)?;
+    m.add_function(wrap_pyfunction!(classify_links, m)?)?;
+    m.add_function(wrap_pyfunction!(unwrap_paragraphs, m)?)?;
+    m.add_function(wrap_pyfunction!(extract_open_graph, m)?)?;
+    m.add_function(wrap_pyfunction!(short_hash, m)?)?;
+    m.add_function(wrap_pyfunction!(normalize_class_list, m)?)?;
+    m.add_function(wrap_pyfunction!(has_viewport_meta, m)?)?;
+    m.add_function(wrap_pyfunction!(extract_viewport, m)?)?;
+    m.add_function(wrap_pyfunction!(split_by_byte_limit, m)?)?;
+    m.add_function(wrap_pyfunction!(render_status_html, m)?)?;
+    m.add_function(wrap_pyfunction!(sort_key, m)?)?;
+    m.add_function(wrap_pyfunction!(audit_image_alts, m)?)?;
+    m.add_function(wrap_pyfunction!(text_stats, m)?)?;
+    m.add_function(wrap_pyfunction!(parse_fence_info, m)?)?;
+    m.add_function(wrap_pyfunction!(identity_color, m)?)?;
+    m.add_function(wrap_pyfunction!(json_has_keys, m)?)?;
+    m.add_function(wrap_pyfunction!(extract_code_and_output, m)?)?;
+    m.add_function(wrap_pyfunction!(split_complete_utf8, m)?)?;
+    m.add_function(wrap_pyfunction!(longest_repeated_substring, m)?)?;
+    m.add_function(wrap_pyfunction!(extract_phone_numbers, m)?)?;
+    m.add_function(wrap_pyfunction!(keyword_diff, m)?)?;
+    m.add_function(wrap_pyfunction!(sanitize_inline_style, m)?)?;
+    m.add_function(wrap_pyfunction!(canonicalize_html, m)?)?;
+    m.add_function(wrap_pyfunction!(extract_quotes, m)?)?;
+    m.add_function(wrap_pyfunction!(plaintext_to_html_offset, m)?)?;
+    m.add_function(wrap_pyfunction!(extract_css_variables, m)?)?;
+    m.add_function(wrap_pyfunction!(collect_used_classes, m)?)?;
+    m.add_function(wrap_pyfunction!(split_thinking, m)?)?;
+    m.add_function(wrap_pyfunction!(base64_decoded_len, m)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_url() {
+        let text = "navigate to example.com for more info";
+        let pattern = r"(?:navigate|go|open)\s+(?:to\s+)?([^\s]+\.[a-z]{2,})";
+        let result = extract_url_from_text(text, pattern).unwrap();
+        assert_eq!(result, Some("example.com".to_string()));
+    }
+
+    #[test]
+    fn test_fast_string_contains() {
+        let text = "create a todo list";
+        let keywords: HashSet<String> = ["create", "make", "generate"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert!(fast_string_contains(text, keywords));
+    }
+
+    #[test]
+    fn test_markdown_to_html() {
+        let text = "This is **bold** and *italic* text";
+        let result = markdown_to_html(text, false);
+        assert!(result.contains("<strong>bold</strong>"));
+        assert!(result.contains("<em>italic</em>"));
+    }
+
+    #[test]
+    fn test_markdown_to_html_batch() {
+        let texts = vec!["**one**", "*two*", "plain", ""];
+        pyo3::prepare_freethreaded_python();
+        let batch = Python::with_gil(|py| markdown_to_html_batch(py, texts.clone(), false));
+        let single: Vec<String> = texts
+            .iter()
+            .map(|text| markdown_to_html(text, false))
+            .collect();
+        assert_eq!(batch, single);
+    }
+
+    #[test]
+    fn test_base64_encode() {
+        let data = b"Hello, World!";
+        let result = base64_encode_optimized(data);
+        assert_eq!(result, "SGVsbG8sIFdvcmxkIQ==");
+    }
+
+    #[test]
+    fn test_common_url_prefix() {
+        let urls = vec![
+            "example.com/a/bc".to_string(),
+            "example.com/a/bd".to_string(),
+        ];
+        assert_eq!(common_url_prefix(urls), "example.com/a");
+
+        let urls = vec![
+            "https://example.com/docs/rust/intro".to_string(),
+            "https://example.com/docs/rust/async?page=2".to_string(),
+            "https://example.com/docs/python".to_string(),
+        ];
+        assert_eq!(common_url_prefix(urls), "https://example.com/docs");
+    }
+
+    #[test]
+    fn test_common_url_prefix_differing_hosts() {
+        let urls = vec![
+            "https://example.com/a".to_string(),
+            "https://example.org/a".to_string(),
+        ];
+        assert_eq!(common_url_prefix(urls), "");
+        assert_eq!(common_url_prefix(Vec::new()), "");
+    }
+
+    #[test]
+    fn test_common_url_prefix_normalizes_urls() {
+        let urls = vec![
+            "https://A.com:443/x/y".to_string(),
+            "https://a.com/x/z".to_string(),
+        ];
+        assert_eq!(common_url_prefix(urls), "https://a.com/x");
+
+        let urls = vec![
+            "https://example.com/caf%65/a".to_string(),
+            "https://example.com/cafe/b".to_string(),
+        ];
+        assert_eq!(common_url_prefix(urls), "https://example.com/caf%65");
+
+        let urls = vec![
+            "https://bob@example.com/a".to_string(),
+            "https://alice@example.com/a".to_string(),
+        ];
+        assert_eq!(common_url_prefix(urls), "");
+
+        let urls = vec![
+            "http://example.com:8080/a".to_string(),
+            "http://example.com/a".to_string(),
+        ];
+        assert_eq!(common_url_prefix(urls), "");
+    }
+
+    #[test]
+    fn test_extract_toc() {
+        let html = "<h1>Getting Started</h1><p>intro</p>\
+                    <h2>Setup &amp; <em>Install</em></h2>\
+                    <h2>Getting Started</h2>";
+        let toc = extract_toc(html);
+        assert_eq!(
+            toc,
+            vec![
+                (
+                    1,
+                    "Getting Started".to_string(),
+                    "getting-started".to_string()
+                ),
+                (
+                    2,
+                    "Setup & Install".to_string(),
+                    "setup-install".to_string()
+                ),
+                (
+                    2,
+                    "Getting Started".to_string(),
+                    "getting-started-1".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_add_heading_anchors() {
+        let html = "<h1 class=\"title\">Intro</h1>\
+                    <h2>Details of <code>foo</code></h2>\
+                    <h2>Intro</h2>\
+                    <h3 id=\"custom\">Kept</h3>";
+        let result = add_heading_anchors(html);
+        assert!(result.contains("<h1 class=\"title\" id=\"intro\">Intro</h1>"));
+        assert!(result.contains("<h2 id=\"details-of-foo\">Details of <code>foo</code></h2>"));
+        assert!(result.contains("<h2 id=\"intro-1\">Intro</h2>"));
+        assert!(result.contains("<h3 id=\"custom\">Kept</h3>"));
+
+        for (_, _, anchor) in extract_toc(html) {
+            assert!(result.contains(&format!("id=\"{}\"", anchor)));
+        }
+    }
+
+    #[test]
+    fn test_heading_anchors_avoid_existing_ids() {
+        assert_eq!(
+            add_heading_anchors("<h2>Intro</h2><h2 id=\"intro\">X</h2>"),
+            "<h2 id=\"intro-1\">Intro</h2><h2 id=\"intro\">X</h2>"
+        );
+
+        let anchors: Vec<String> =
+            extract_toc("<h2 id=\"intro\">X</h2><h2>Intro</h2><h2 id=\"intro-1\">Y</h2>")
+                .into_iter()
+                .map(|(_, _, anchor)| anchor)
+                .collect();
+        assert_eq!(anchors, vec!["intro", "intro-2", "intro-1"]);
+    }
+
+    #[test]
+    fn test_collapse_html_whitespace() {
+        let html = "<div   class=\"a  b\">\n\n   Hello \t  world  </div>\n\n<p>x</p>";
+        assert_eq!(
+            collapse_html_whitespace(html),
+            "<div   class=\"a  b\"> Hello world </div> <p>x</p>"
+        );
+    }
+
+    #[test]
+    fn test_collapse_html_whitespace_preserves_pre() {
+        let html = "<p>a    b</p><pre>  fn main() {\n      x\n  }</pre><code>a  b</code>";
+        assert_eq!(
+            collapse_html_whitespace(html),
+            "<p>a b</p><pre>  fn main() {\n      x\n  }</pre><code>a  b</code>"
+        );
+    }
+
+    #[test]
+    fn test_html_tokenizer_round_trips() {
+        let html = "<!DOCTYPE html><p a='>'>1 < 2</p><script>if (a<b) {}</script><!-- c -->";
+        let rebuilt: String = HtmlTokenizer::new(html).map(|token| token.raw()).collect();
+        assert_eq!(rebuilt, html);
+        assert!(HtmlTokenizer::new(html).any(|token| token == HtmlToken::Text("if (a<b) {}")));
+    }
+
+    #[test]
+    fn test_html_tokenizer_multibyte_text() {
+        let html = "é<b>日本</b><p>«x» < ü</p>";
+        let rebuilt: String = HtmlTokenizer::new(html).map(|token| token.raw()).collect();
+        assert_eq!(rebuilt, html);
+        assert_eq!(HtmlTokenizer::new(html).next(), Some(HtmlToken::Text("é")));
+        assert_eq!(collapse_html_whitespace("<p>é  x</p>"), "<p>é x</p>");
+    }
+
+    #[test]
+    fn test_css_escape() {
+        assert_eq!(css_escape(r#"say "hi" \o/"#), r#"say \22 hi\22  \5c o/"#);
+        assert_eq!(css_escape("a\nb</style>"), "a\\a b\\3c /style\\3e ");
+        assert_eq!(css_escape("plain-text_123"), "plain-text_123");
+    }
+
+    #[test]
+    fn test_canonicalize_base64() {
+        let wrapped = "SGVsbG8s\nIFdvcmxk\r\nIQ==\n";
+        assert_eq!(
+            canonicalize_base64(wrapped).unwrap(),
+            "SGVsbG8sIFdvcmxkIQ=="
+        );
+
+        // URL-safe alphabet without padding
+        assert_eq!(canonicalize_base64("-_-_").unwrap(), "+/+/");
+        assert_eq!(canonicalize_base64("SGk").unwrap(), "SGk=");
+
+        assert!(canonicalize_base64("SGVsb").is_err());
+        assert!(canonicalize_base64("SG!k").is_err());
+    }
+
+    #[test]
+    fn test_extract_numbers() {
+        assert_eq!(
+            extract_numbers("set zoom to 150% and margin to 20px"),
+            vec![
+                (150.0, Some("%".to_string())),
+                (20.0, Some("px".to_string()))
+            ]
+        );
+        assert_eq!(extract_numbers("-3.5"), vec![(-3.5, None)]);
+        assert_eq!(extract_numbers("open the h2 heading"), Vec::new());
+    }
+
+    #[test]
+    fn test_extract_numbers_separators_exponents_and_ranges() {
+        assert_eq!(
+            extract_numbers("1,000,000 users"),
+            vec![(1_000_000.0, None)]
+        );
+        assert_eq!(extract_numbers("-4.5e3 kg"), vec![(-4500.0, None)]);
+        assert_eq!(extract_numbers("2em"), vec![(2.0, Some("em".to_string()))]);
+        assert_eq!(extract_numbers("pages 3-5"), vec![(3.0, None), (5.0, None)]);
+    }
+
+    #[test]
+    fn test_locate_code_blocks() {
+        let text = "Intro\n```python\nprint(1)\nprint(2)\n```\nSome prose\n\n~~~\nls -la\n~~~\n";
+        assert_eq!(
+            locate_code_blocks(text),
+            vec![
+                (2, 5, "python".to_string(), "print(1)\nprint(2)".to_string()),
+                (8, 10, String::new(), "ls -la".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expand_template() {
+        let vars: HashMap<String, String> = [("name", "Ada"), ("site", "<b>docs</b>")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+
+        assert_eq!(
+            expand_template(
+                "Hi {{name}}, see {{ site }}. {{missing}}",
+                vars.clone(),
+                false
+            ),
+            "Hi Ada, see <b>docs</b>. {{missing}}"
+        );
+        assert_eq!(
+            expand_template("{{site}}", vars, true),
+            "&lt;b&gt;docs&lt;/b&gt;"
+        );
+    }
+
+    #[test]
+    fn test_detect_charset() {
+        assert_eq!(
+            detect_charset(b"\xEF\xBB\xBFhello"),
+            Some("UTF-8".to_string())
+        );
+        assert_eq!(
+            detect_charset(b"\xFF\xFEh\x00i\x00"),
+            Some("UTF-16LE".to_string())
+        );
+        assert_eq!(
+            detect_charset(b"Le caf\xE9 est tr\xE8s bon, d\xE9j\xE0 servi \xE0 la fen\xEAtre."),
+            Some("windows-1252".to_string())
+        );
+        assert_eq!(detect_charset(b""), None);
+    }
+
+    #[test]
+    fn test_decode_bytes() {
+        // 0x80 is the euro sign in windows-1252 but a control character in Latin-1
+        assert_eq!(
+            decode_bytes(b"5 \x80 caf\xE9", "windows-1252").unwrap(),
+            "5 € café"
+        );
+        assert_eq!(decode_bytes(b"ok \xFF", "utf-8").unwrap(), "ok \u{FFFD}");
+        assert!(decode_bytes(b"abc", "not-a-charset").is_err());
+    }
+
+    #[test]
+    fn test_extract_meta_description() {
+        let html = r#"<head><meta content="Fast &amp; small" name='description'></head>"#;
+        assert_eq!(
+            extract_meta_description(html),
+            Some("Fast & small".to_string())
+        );
+
+        let html = r#"<meta name="description" content="plain">
+                      <META PROPERTY="og:description" CONTENT="From OG">"#;
+        assert_eq!(extract_meta_description(html), Some("From OG".to_string()));
+
+        let html = r#"<meta charset="utf-8"><title>No description</title>"#;
+        assert_eq!(extract_meta_description(html), None);
+    }
+
+    #[test]
+    fn test_simhash_near_duplicates() {
+        let original = "The quick brown fox jumps over the lazy dog while the farmer watches \
+                        from the porch and the sun slowly sets behind the distant rolling hills \
+                        of the quiet countryside on a warm summer evening";
+        let edited = "The quick brown fox leaps over the lazy dog while the farmer watches \
+                      from the porch and the sun slowly sets behind the distant rolling hills \
+                      of the quiet countryside on a warm summer evening";
+        let unrelated = "Rust ownership rules guarantee memory safety without a garbage \
+                         collector by tracking lifetimes of borrowed references at compile \
+                         time across functions modules and crates in large codebases";
+
+        assert_eq!(simhash(original), simhash(original));
+        assert!(hamming_distance(simhash(original), simhash(edited)) < 12);
+        assert!(hamming_distance(simhash(original), simhash(unrelated)) > 20);
+    }
+
+    #[test]
+    fn test_hamming_distance() {
+        assert_eq!(hamming_distance(0, 0), 0);
+        assert_eq!(hamming_distance(0b1011, 0b0001), 2);
+        assert_eq!(hamming_distance(0, u64::MAX), 64);
+    }
+
+    #[test]
+    fn test_markdown_to_plaintext_links() {
+        let text = "See [the docs](https://example.com/docs) for **more**.";
+        assert_eq!(
+            markdown_to_plaintext(text, true),
+            "See the docs (https://example.com/docs) for more."
+        );
+        assert_eq!(markdown_to_plaintext(text, false), "See the docs for more.");
+    }
+
+    #[test]
+    fn test_markdown_to_plaintext_lists_and_code() {
+        let text = "# Steps\n\n* first *step*\n  + nested\n1. numbered\n\n```sh\ncargo test\n```";
+        assert_eq!(
+            markdown_to_plaintext(text, false),
+            "Steps\n\n- first step\n- nested\n- numbered\n\ncargo test"
+        );
+    }
+
+    #[test]
+    fn test_is_valid_email() {
+        assert!(is_valid_email("user@example.com"));
+        assert!(is_valid_email("first.last+tag@mail.example.co.uk"));
+
+        assert!(!is_valid_email("user.example.com"));
+        assert!(!is_valid_email("user@example.com."));
+        assert!(!is_valid_email("user@example..com"));
+        assert!(!is_valid_email("user@localhost"));
+        assert!(!is_valid_email(".user@example.com"));
+        assert!(!is_valid_email("a@b@example.com"));
+    }
+
+    #[test]
+    fn test_split_args() {
+        assert_eq!(
+            split_args(r#"search "rust async" --limit 5"#).unwrap(),
+            vec!["search", "rust async", "--limit", "5"]
+        );
+        assert_eq!(
+            split_args(r#"open my\ file.txt 'it''s' "say \"hi\"" """#).unwrap(),
+            vec!["open", "my file.txt", "its", "say \"hi\"", ""]
+        );
+        assert!(split_args("search \"unterminated").is_err());
+        assert!(split_args("search 'unterminated").is_err());
+    }
+
+    #[test]
+    fn test_display_width() {
+        assert_eq!(display_width("hello"), 5);
+        assert_eq!(display_width("日本語"), 6);
+        assert_eq!(display_width("e\u{301}te\u{301}"), 3);
+    }
+
+    #[test]
+    fn test_wrap_text() {
+        assert_eq!(
+            wrap_text("the quick brown fox\n\njumps over", 10, false),
+            "the quick\nbrown fox\n\njumps over"
+        );
+        assert_eq!(
+            wrap_text("a extraordinarily b", 5, false),
+            "a\nextraordinarily\nb"
+        );
+        // Four CJK characters are eight columns wide
+        assert_eq!(wrap_text("日本語で 書く", 8, false), "日本語で 書く");
+        assert_eq!(wrap_text("日本語で 書く", 8, true), "日本語で\n書く");
+        assert_eq!(
+            wrap_text("e\u{301}te\u{301} ok", 6, true),
+            "e\u{301}te\u{301} ok"
+        );
+    }
+
+    #[test]
+    fn test_char_byte_offsets() {
+        let text = "héllo 日本";
+        assert_eq!(char_to_byte_offset(text, 0), Some(0));
+        assert_eq!(char_to_byte_offset(text, 2), Some(3));
+        assert_eq!(char_to_byte_offset(text, 7), Some(10));
+        assert_eq!(char_to_byte_offset(text, 8), Some(13));
+        assert_eq!(char_to_byte_offset(text, 9), None);
+
+        assert_eq!(byte_to_char_offset(text, 3), Some(2));
+        assert_eq!(byte_to_char_offset(text, 10), Some(7));
+        assert_eq!(byte_to_char_offset(text, 13), Some(8));
+        assert_eq!(byte_to_char_offset(text, 2), None);
+        assert_eq!(byte_to_char_offset(text, 14), None);
+    }
+
+    #[test]
+    fn test_wrap_matches() {
+        let result = wrap_matches(
+            "error: disk full, error again",
+            r"error",
+            "\x1b[31m",
+            "\x1b[0m",
+        );
+        assert_eq!(
+            result.unwrap(),
+            "\x1b[31merror\x1b[0m: disk full, \x1b[31merror\x1b[0m again"
+        );
+
+        let untouched = wrap_matches("nothing to see", r"\d+", "**", "**").unwrap();
+        assert_eq!(untouched, "nothing to see");
+        assert_eq!(wrap_matches("a1b", r"\d*", "[", "]").unwrap(), "a[1]b");
+        assert!(wrap_matches("text", r"(unclosed", "", "").is_err());
+    }
+
+    #[test]
+    fn test_parse_markdown_table() {
+        let text = "| Name | Score |\n|:-----|------:|\n| Ada  | 10 |\n| Bob \\| Jr | 7 |\n";
+        let (header, rows) = parse_markdown_table(text).unwrap();
+        assert_eq!(header, vec!["Name", "Score"]);
+        assert_eq!(rows, vec![vec!["Ada", "10"], vec!["Bob | Jr", "7"]]);
+    }
+
+    #[test]
+    fn test_parse_markdown_table_invalid() {
+        assert_eq!(parse_markdown_table("| a | b |\n| c | d |"), None);
+        assert_eq!(parse_markdown_table("| a | b |\n| --- |"), None);
+        assert_eq!(parse_markdown_table("just some text"), None);
+    }
+
+    #[test]
+    fn test_is_safe_navigation_url() {
+        assert!(is_safe_navigation_url("https://example.com", None));
+        assert!(is_safe_navigation_url("FILE:///tmp/page.html", None));
+
+        assert!(!is_safe_navigation_url("javascript:alert(1)", None));
+        assert!(!is_safe_navigation_url("java\tscript:alert(1)", None));
+        assert!(!is_safe_navigation_url(" \x01JaVaScRiPt:alert(1)", None));
+        assert!(!is_safe_navigation_url("data:text/html,<script>", None));
+        assert!(!is_safe_navigation_url("vbscript:msgbox", None));
+        assert!(!is_safe_navigation_url("//evil.example.com", None));
+
+        let only_https: HashSet<String> = ["https".to_string()].into_iter().collect();
+        assert!(!is_safe_navigation_url(
+            "http://example.com",
+            Some(only_https)
+        ));
+    }
+
+    #[test]
+    fn test_extract_all_groups() {
+        let pattern = r"(\w+)://(?:(\w+)@)?([\w.]+)";
+        let groups = extract_all_groups("visit https://example.com now", pattern).unwrap();
+        assert_eq!(
+            groups,
+            Some(vec![
+                Some("https://example.com".to_string()),
+                Some("https".to_string()),
+                None,
+                Some("example.com".to_string()),
+            ])
+        );
+        assert_eq!(extract_all_groups("no url here", pattern).unwrap(), None);
+        assert!(extract_all_groups("text", r"(unclosed").is_err());
+    }
+
+    #[test]
+    fn test_word_diff_substitution() {
+        let ops: Vec<(String, String)> = word_diff("the quick brown fox", "the slow brown fox");
+        let expected: Vec<(String, String)> = [
+            ("equal", "the"),
+            ("removed", "quick"),
+            ("added", "slow"),
+            ("equal", "brown"),
+            ("equal", "fox"),
+        ]
+        .iter()
+        .map(|(op, token)| (op.to_string(), token.to_string()))
+        .collect();
+        assert_eq!(ops, expected);
+    }
+
+    #[test]
+    fn test_word_diff_appended_sentence() {
+        let ops = word_diff("Hello there.", "Hello there. How are you?");
+        let added: Vec<&str> = ops
+            .iter()
+            .filter(|(op, _)| op == "added")
+            .map(|(_, token)| token.as_str())
+            .collect();
+        assert_eq!(added, vec!["How", "are", "you?"]);
+        assert!(ops.iter().all(|(op, _)| op != "removed"));
+    }
+
+    #[test]
+    fn test_diff_sequences_caps_table_size() {
+        let old: Vec<u32> = (0..20_000).collect();
+        let new: Vec<u32> = old
+            .iter()
+            .map(|&x| {
+                if (5_000..15_000).contains(&x) {
+                    x + 100_000
+                } else {
+                    x
+                }
+            })
+            .collect();
+        let ops = diff_sequences(&old, &new);
+        assert_eq!(ops.len(), 30_000);
+        assert_eq!(ops[4_999], DiffOp::Equal(4_999, 4_999));
+        assert_eq!(ops[5_000], DiffOp::Removed(5_000));
+        assert_eq!(ops[15_000], DiffOp::Added(5_000));
+        assert_eq!(ops[25_000], DiffOp::Equal(15_000, 15_000));
+    }
+
+    #[test]
+    fn test_is_balanced_html() {
+        assert!(is_balanced_html(
+            "<div><p>Hi <b>there</b></p><ul><li>x</li></ul></div>"
+        ));
+        assert!(is_balanced_html("<p>line<br>break <img src=\"a.png\"></p>"));
+        assert!(!is_balanced_html("<div><p>unclosed</div>"));
+        assert!(!is_balanced_html("<b><i>crossed</b></i>"));
+        assert!(!is_balanced_html("<section>"));
+    }
+
+    #[test]
+    fn test_extract_favicon() {
+        let html = r#"<head><link rel="stylesheet" href="style.css">
+                      <link href="../img/icon.png" rel="shortcut icon"></head>"#;
+        assert_eq!(
+            extract_favicon(html, "https://example.com/docs/page.html"),
+            Some("https://example.com/img/icon.png".to_string())
+        );
+    }
+
+    #[test]
+    fn test_extract_favicon_fallback() {
+        let html = "<head><title>No icon</title></head>";
+        assert_eq!(
+            extract_favicon(html, "https://example.com/a/b"),
+            Some("https://example.com/favicon.ico".to_string())
+        );
+        assert_eq!(extract_favicon(html, "not a url"), None);
+    }
+
+    #[test]
+    fn test_resolve_url() {
+        let base = "https://example.com/docs/guide/intro.html";
+        assert_eq!(
+            resolve_url(base, "../x").unwrap(),
+            "https://example.com/docs/x"
+        );
+        assert_eq!(
+            resolve_url(base, "/about").unwrap(),
+            "https://example.com/about"
+        );
+        assert_eq!(
+            resolve_url(base, "//cdn.example.com/lib.js").unwrap(),
+            "https://cdn.example.com/lib.js"
+        );
+        assert!(resolve_url("docs/intro.html", "x").is_err());
+    }
+
+    #[test]
+    fn test_extract_links() {
+        let html = r#"<nav><a href="https://rust-lang.org">Rust <b>home</b></a>
+                      <a href="../guide/">The &amp; guide</a>
+                      <a name="anchor-only">no href</a>
+                      <a href="javascript:void(0)">click</a></nav>"#;
+        assert_eq!(
+            extract_links(html, "https://example.com/docs/page.html"),
+            vec![
+                (
+                    "https://rust-lang.org/".to_string(),
+                    "Rust home".to_string()
+                ),
+                (
+                    "https://example.com/guide/".to_string(),
+                    "The & guide".to_string()
+                ),
+            ]
+        );
+    }
+
+    fn apply_list_patch(mut list: Vec<String>, patch: &[(String, usize, String)]) -> Vec<String> {
+        for (op, index, value) in patch {
+            match op.as_str() {
+                "insert" => list.insert(*index, value.clone()),
+                "delete" => {
+                    list.remove(*index);
+                }
+                other => panic!("unexpected op {}", other),
+            }
+        }
+        list
+    }
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_list_patch() {
+        let old = strings(&["a", "b", "c"]);
+
+        let insertion = strings(&["a", "x", "b", "c"]);
+        let patch = list_patch(old.clone(), insertion.clone());
+        assert_eq!(patch, vec![("insert".to_string(), 1, "x".to_string())]);
+
+        let deletion = strings(&["a", "c"]);
+        let patch = list_patch(old.clone(), deletion.clone());
+        assert_eq!(patch, vec![("delete".to_string(), 1, String::new())]);
+
+        let reordered = strings(&["c", "a", "b"]);
+        let patch = list_patch(old.clone(), reordered.clone());
+        assert_eq!(patch.len(), 2);
+        assert_eq!(apply_list_patch(old, &patch), reordered);
+    }
+
+    #[test]
+    fn test_entry_id() {
+        let id = entry_id("user", "hello", 1_700_000_000);
+        assert_eq!(id.len(), 16);
+        assert_eq!(id, entry_id("user", "hello", 1_700_000_000));
+        assert_ne!(id, entry_id("user", "hello!", 1_700_000_000));
+        assert_ne!(entry_id("ab", "c", 0), entry_id("a", "bc", 0));
+    }
+
+    #[test]
+    fn test_base32_encode() {
+        assert_eq!(base32_encode(b""), "");
+        assert_eq!(base32_encode(b"f"), "my");
+        assert_eq!(base32_encode(b"foobar"), "mzxw6ytboi");
+    }
+
+    #[test]
+    fn test_markdown_to_html_task_list() {
+        let text = "Todo:\n- [x] write **tests**\n- [ ] ship it\nDone.";
+        assert_eq!(
+            markdown_to_html(text, false),
+            "Todo:\n<ul class=\"task-list\">\n\
+             <li><input type=\"checkbox\" disabled checked>write <strong>tests</strong></li>\n\
+             <li><input type=\"checkbox\" disabled>ship it</li>\n</ul>\nDone."
+        );
+    }
+
+    #[test]
+    fn test_detect_repetition() {
+        let looping = "Sure, here is the answer. \
+                       I am happy to help. I am happy to help. I am happy to help.";
+        assert_eq!(
+            detect_repetition(looping, 3, 3),
+            Some("I am happy to help.".to_string())
+        );
+
+        let normal = "The browser renders pages quickly and the AI answers questions clearly.";
+        assert_eq!(detect_repetition(normal, 2, 2), None);
+    }
+
+    #[test]
+    fn test_detect_repetition_at_the_tail() {
+        let cut_off = "Intro. again and again and again and";
+        assert_eq!(
+            detect_repetition(cut_off, 2, 3),
+            Some("again and".to_string())
+        );
+
+        let recovered = "stop stop stop. Then the answer continues normally.";
+        assert_eq!(detect_repetition(recovered, 1, 3), None);
+
+        let long: String = (0..200_000).map(|i| format!("w{} ", i)).collect();
+        assert_eq!(detect_repetition(&long, 1, 2), None);
+        let looping = format!("{}{}", long, "so it goes ".repeat(4));
+        assert_eq!(
+            detect_repetition(&looping, 2, 3),
+            Some("so it goes".to_string())
+        );
+    }
+
+    #[test]
+    fn test_markdown_to_html_horizontal_rules() {
+        assert_eq!(
+            markdown_to_html("above\n---\nbelow", false),
+            "above\n<hr>\nbelow"
+        );
+        assert_eq!(markdown_to_html("* * *", false), "<hr>");
+        assert_eq!(markdown_to_html("___", false), "<hr>");
+        assert_eq!(markdown_to_html("--", false), "--");
+        assert_eq!(markdown_to_html("- - x", false), "- - x");
+        assert_eq!(markdown_to_html("   ---", false), "<hr>");
+        assert_eq!(markdown_to_html("    ---", false), "    ---");
+        assert_eq!(markdown_to_html("\t---", false), "\t---");
+    }
+
+    #[test]
+    fn test_markdown_to_html_skips_frontmatter() {
+        let text = "---\ntitle: Notes\ntags: [a, b]\n---\nIntro\n\n---\n\nMore";
+        assert_eq!(
+            markdown_to_html(text, false),
+            "---\ntitle: Notes\ntags: [a, b]\n---\nIntro\n\n<hr>\n\nMore"
+        );
+        assert_eq!(markdown_to_html("---\nunclosed", false), "<hr>\nunclosed");
+    }
+
+    #[test]
+    fn test_detect_language() {
+        let english = "This is a simple paragraph written in English. It explains how the \
+                       browser shows answers from the assistant while they are being written.";
+        let (code, confidence) = detect_language(english).unwrap();
+        assert_eq!(code, "eng");
+        assert!(confidence > 0.0 && confidence <= 1.0);
+
+        let french = "Le navigateur affiche la réponse dès que le modèle a fini, \
+                      et vous pouvez continuer à lire pendant ce temps.";
+        assert_eq!(detect_language(french).unwrap().0, "fra");
+
+        assert_eq!(detect_language("hello there"), None);
+    }
+
+    #[test]
+    fn test_clean_tracking_params() {
+        let cleaned = clean_tracking_params(
+            "https://example.com/search?utm_source=news&q=rust%20async&fbclid=abc&ref=x#top",
+            ["ref".to_string()].into_iter().collect(),
+        );
+        assert_eq!(
+            cleaned.unwrap(),
+            "https://example.com/search?q=rust%20async#top"
+        );
+
+        let cleaned = clean_tracking_params("https://example.com/a?UTM_Medium=x", HashSet::new());
+        assert_eq!(cleaned.unwrap(), "https://example.com/a");
+
+        let plain = "https://example.com/page";
+        assert_eq!(clean_tracking_params(plain, HashSet::new()).unwrap(), plain);
+        assert!(clean_tracking_params("not a url", HashSet::new()).is_err());
+    }
+
+    #[test]
+    fn test_group_match_values() {
+        let text = "id=1, id=22, name=x, id=333";
+        assert_eq!(
+            group_match_values(text, r"id=(\d+)", 1).unwrap(),
+            vec!["1", "22", "333"]
+        );
+        assert_eq!(
+            group_match_values("a1 b c3", r"[a-z](\d)?", 1).unwrap(),
+            vec!["1", "3"]
+        );
+        assert!(group_match_values(text, r"id=(\d+)", 2).is_err());
+        assert!(group_match_values(text, r"(unclosed", 0).is_err());
+    }
+
+    #[test]
+    fn test_flesch_reading_ease() {
+        let simple = flesch_reading_ease("The cat sat on the mat. It was a good day.");
+        let complex = flesch_reading_ease(
+            "Notwithstanding considerable institutional opposition, the international \
+             organization systematically implemented comprehensive administrative \
+             reorganization initiatives throughout subsequent operational evaluations.",
+        );
+        assert!(simple > 90.0, "simple text scored {}", simple);
+        assert!(complex < 10.0, "complex text scored {}", complex);
+
+        assert_eq!(flesch_reading_ease(""), 0.0);
+        assert!(flesch_reading_ease("Hello world").is_finite());
+    }
+
+    #[test]
+    fn test_estimate_syllables() {
+        assert_eq!(estimate_syllables("cat"), 1);
+        assert_eq!(estimate_syllables("table"), 2);
+        assert_eq!(estimate_syllables("make"), 1);
+        assert_eq!(estimate_syllables("reading"), 2);
+    }
+
+    #[test]
+    fn test_build_attributes() {
+        let pairs = vec![
+            ("title".to_string(), "Say \"hi\" & <wave>\nbye".to_string()),
+            ("data-id".to_string(), "42".to_string()),
+        ];
+        assert_eq!(
+            build_attributes(pairs).unwrap(),
+            "title=\"Say &quot;hi&quot; &amp; &lt;wave&gt;&#10;bye\" data-id=\"42\""
+        );
+
+        assert!(build_attributes(vec![("on click".to_string(), "x".to_string())]).is_err());
+        assert!(build_attributes(vec![("a\"b".to_string(), "x".to_string())]).is_err());
+        assert_eq!(build_attributes(Vec::new()).unwrap(), "");
+    }
+
+    #[test]
+    fn test_extract_jsonld() {
+        let html = r#"<head><script type="Application/LD+JSON">
+            {"@type": "Article", "headline": "a < b"}
+            </script><script>var x = 1;</script>
+            <script type='application/ld+json'>{"@type": "Person"}</script></head>"#;
+        assert_eq!(
+            extract_jsonld(html),
+            vec![
+                r#"{"@type": "Article", "headline": "a < b"}"#,
+                r#"{"@type": "Person"}"#,
+            ]
+        );
+        assert!(extract_jsonld("<p>No structured data</p>").is_empty());
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(0, false), "0 B");
+        assert_eq!(format_bytes(999, false), "999 B");
+        assert_eq!(format_bytes(1500, false), "1.5 KB");
+        assert_eq!(format_bytes(1_048_576, true), "1.0 MiB");
+        assert_eq!(format_bytes(1_048_576, false), "1.0 MB");
+        assert_eq!(format_bytes(999_960, false), "1.0 MB");
+        assert_eq!(format_bytes(5_000_000_000_000_000, false), "5000.0 TB");
+    }
+
+    #[test]
+    fn test_url_cache_key() {
+        let key = url_cache_key("https://Example.com/search?q=rust&page=2#results").unwrap();
+        assert_eq!(key.len(), 64);
+        assert_eq!(
+            key,
+            url_cache_key("https://example.com/search?page=2&q=rust").unwrap()
+        );
+        assert_ne!(
+            key,
+            url_cache_key("https://example.com/other?page=2&q=rust").unwrap()
+        );
+        assert!(url_cache_key("not a url").is_err());
+    }
+
+    #[test]
+    fn test_title_from_text() {
+        assert_eq!(
+            title_from_text("rust makes **systems** programming safe and fun.", 4),
+            "Rust makes systems programming…"
+        );
+        assert_eq!(
+            title_from_text("\n\nShort answer.\nMore details.", 5),
+            "Short answer."
+        );
+
+        let fenced = "```python\nimport os\n```\n## using the os module\nIt lists files.";
+        assert_eq!(title_from_text(fenced, 3), "Using the os…");
+        assert_eq!(title_from_text("```\ncode only\n```", 3), "");
+        assert_eq!(title_from_text("Short answer.", 0), "");
+    }
+
+    #[test]
+    fn test_normalize_color() {
+        assert_eq!(normalize_color("#f00"), Some("#ff0000".to_string()));
+        assert_eq!(normalize_color("#1A2b3C"), Some("#1a2b3c".to_string()));
+        assert_eq!(normalize_color("rgb(300,0,0)"), Some("#ff0000".to_string()));
+        assert_eq!(
+            normalize_color("RGB(12 34 -5)"),
+            Some("#0c2200".to_string())
+        );
+        assert_eq!(normalize_color("Navy"), Some("#000080".to_string()));
+
+        assert_eq!(normalize_color("notacolor"), None);
+        assert_eq!(normalize_color("#12345"), None);
+        assert_eq!(normalize_color("rgb(1, 2)"), None);
+    }
+
+    #[test]
+    fn test_extract_stylesheets() {
+        let html = r#"<head>
+            <link rel="stylesheet" href="css/main.css">
+            <link rel="icon" href="favicon.png">
+            <link href="https://cdn.example.com/reset.css" rel="Stylesheet">
+            <style>@import url("print.css"); body { color: red }</style>
+            </head>"#;
+        assert_eq!(
+            extract_stylesheets(html, "https://example.com/blog/post.html"),
+            vec![
+                "https://example.com/blog/css/main.css",
+                "https://cdn.example.com/reset.css",
+                "https://example.com/blog/print.css",
+            ]
+        );
+        assert!(extract_stylesheets("<style>p { margin: 0 }</style>", "https://x.com/").is_empty());
+    }
+
+    #[test]
+    fn test_merge_keyword_sets() {
+        let to_set = |items: &[&str]| items.iter().map(|s| s.to_string()).collect::<HashSet<_>>();
+        let sets = vec![
+            to_set(&["Create", "make"]),
+            to_set(&["make", "Build"]),
+            to_set(&["create", "GENERATE"]),
+        ];
+
+        let merged = merge_keyword_sets(sets.clone(), true);
+        assert_eq!(merged, to_set(&["create", "make", "build", "generate"]));
+
+        let merged = merge_keyword_sets(sets, false);
+        assert_eq!(merged.len(), 5);
+    }
+
+    #[test]
+    fn test_analyze_response() {
+        let text = "See https://example.com and http://x.org.\n```rust\nfn main() {}\n```\nDone";
+        let prose = "See https://example.com and http://x.org.".len() + "Done".len();
+        assert_eq!(analyze_response(text), (prose, "fn main() {}".len(), 2));
+        assert_eq!(analyze_response(""), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_markdown_escape() {
+        let escaped = markdown_escape("**not bold** [x](y) a_b");
+        assert_eq!(escaped, r"\*\*not bold\*\* \[x\]\(y\) a\_b");
+        assert_eq!(
+            markdown_to_html(&escaped, false),
+            "&#42;&#42;not bold&#42;&#42; &#91;x&#93;&#40;y&#41; a&#95;b"
+        );
+        assert!(!markdown_to_html(&markdown_escape("*not italic*"), false).contains("<em>"));
+
+        let html = markdown_to_html(&markdown_escape("costs $5 and $6, or $x$"), false);
+        assert_eq!(html, "costs &#36;5 and &#36;6, or &#36;x&#36;");
+        assert!(!html.contains("class=\"math"));
+    }
+
+    #[test]
+    fn test_seeded_shuffle() {
+        let items: Vec<String> = (0..10).map(|i| format!("candidate-{}", i)).collect();
+
+        let first = seeded_shuffle(items.clone(), 42);
+        assert_eq!(first, seeded_shuffle(items.clone(), 42));
+        assert_ne!(first, seeded_shuffle(items.clone(), 43));
+
+        let mut sorted = first.clone();
+        sorted.sort();
+        let mut original = items;
+        original.sort();
+        assert_eq!(sorted, original);
+    }
+
+    #[test]
+    fn test_approximate_token_count() {
+        assert_eq!(approximate_token_count(""), 0);
+        assert_eq!(approximate_token_count("abcd"), 1);
+        assert_eq!(approximate_token_count("abcde"), 2);
+    }
+
+    #[test]
+    fn test_trim_to_budget() {
+        // 10, 5, 5, 5 and 5 estimated tokens
+        let messages: Vec<String> = [40, 20, 20, 20, 20]
+            .iter()
+            .map(|n| "x".repeat(*n))
+            .collect();
+        let trimmed = trim_to_budget(messages.clone(), 16);
+        assert_eq!(trimmed, messages[2..].to_vec());
+
+        assert_eq!(trim_to_budget(messages.clone(), 100), messages);
+        assert_eq!(trim_to_budget(messages.clone(), 1), messages[4..].to_vec());
+        assert!(trim_to_budget(Vec::new(), 10).is_empty());
+    }
+
+    #[test]
+    fn test_markdown_to_html_math() {
+        assert_eq!(
+            markdown_to_html("area is $x^2$ or $a*b*c$", false),
+            "area is <span class=\"math inline\">x^2</span> or \
+             <span class=\"math inline\">a*b*c</span>"
+        );
+        assert_eq!(
+            markdown_to_html("$$\\int_0^1 f(x)\\,dx$$", false),
+            "<div class=\"math block\">\\int_0^1 f(x)\\,dx</div>"
+        );
+        assert_eq!(markdown_to_html("it costs $5", false), "it costs $5");
+        assert_eq!(
+            markdown_to_html("from $5 to $10 *now*", false),
+            "from $5 to $10 <em>now</em>"
+        );
+        assert_eq!(
+            markdown_to_html("\\$5 and \\$10", false),
+            "&#36;5 and &#36;10"
+        );
+        assert_eq!(
+            markdown_to_html("\\$x and \\$y", false),
+            "&#36;x and &#36;y"
+        );
+        assert_eq!(
+            markdown_to_html("$\\$x$ \\\\$y$", false),
+            "<span class=\"math inline\">\\$x</span> &#92;<span class=\"math inline\">y</span>"
+        );
+    }
+
+    #[test]
+    fn test_markdown_to_html_math_ignores_forged_placeholders() {
+        assert_eq!(
+            markdown_to_html("price \u{E000}0\u{E001} $x$", false),
+            "price &#57344;0&#57345; <span class=\"math inline\">x</span>"
+        );
+        assert_eq!(markdown_to_html("a\u{E002}b", false), "a&#57346;b");
+    }
+
+    #[test]
+    fn test_dominant_color() {
+        let mut image = image::RgbImage::from_pixel(16, 16, image::Rgb([0x33, 0x66, 0x99]));
+        image.put_pixel(0, 0, image::Rgb([255, 255, 255]));
+        let mut png = Vec::new();
+        image
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+
+        assert_eq!(dominant_color(&png).unwrap(), "#336699");
+        assert!(dominant_color(b"definitely not an image").is_err());
+    }
+
+    #[test]
+    fn test_parse_headers() {
+        let raw = "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\n\
+                   X-Custom:   padded  \r\nSet-Cookie: a=1\r\nSet-Cookie: b=2\r\n\r\nbody: ignored";
+        assert_eq!(
+            parse_headers(raw),
+            vec![
+                (
+                    "Content-Type".to_string(),
+                    "text/html; charset=utf-8".to_string()
+                ),
+                ("X-Custom".to_string(), "padded".to_string()),
+                ("Set-Cookie".to_string(), "a=1".to_string()),
+                ("Set-Cookie".to_string(), "b=2".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_headers_folded() {
+        let raw = "X-Long: first part\r\n   second part\r\n\tthird\r\nHost: example.com\r\n";
+        assert_eq!(
+            parse_headers(raw),
+            vec![
+                (
+                    "X-Long".to_string(),
+                    "first part second part third".to_string()
+                ),
+                ("Host".to_string(), "example.com".to_string()),
+            ]
+        );
+        let raw = "Host: example.com\r\nbad header\r\n  folded onto it\r\nX (y): z\r\n\tmore\r\n";
+        assert_eq!(
+            parse_headers(raw),
+            vec![("Host".to_string(), "example.com".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_same_origin() {
+        assert!(same_origin("https://x.com", "https://x.com:443/path?q=1").unwrap());
+        assert!(same_origin("https://X.com/a", "https://x.com/b").unwrap());
+        assert!(!same_origin("http://x.com", "https://x.com").unwrap());
+        assert!(!same_origin("https://api.x.com", "https://www.x.com").unwrap());
+        assert!(!same_origin("https://x.com:8443", "https://x.com").unwrap());
+        assert!(same_origin("https://x.com", "not a url").is_err());
+    }
+
+    #[test]
+    fn test_decode_html_entities() {
+        assert_eq!(decode_html_entities("fish &amp; chips"), "fish & chips");
+        assert_eq!(decode_html_entities("&#169; 2024"), "© 2024");
+        assert_eq!(decode_html_entities("&#x1F600;"), "😀");
+        assert_eq!(decode_html_entities("&hellip;&rarr;&eacute;"), "…→é");
+        assert_eq!(decode_html_entities("&notareal; &amp"), "&notareal; &amp");
+    }
+
+    #[test]
+    fn test_svg_to_data_url() {
+        let svg = concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 10 10" onload="x()">"#,
+            "<script>alert(1)</script>",
+            r#"<image href="https://evil.example/a.png"/><circle r="4" fill="url(#g)"/></svg>"#,
+        );
+        let url = svg_to_data_url(svg).unwrap();
+        assert!(url.starts_with("data:image/svg+xml,%3Csvg"));
+        assert!(!url.contains(char::is_whitespace) && !url.contains('<') && !url.contains('"'));
+        assert!(url.contains("viewBox=%220%200%2010%2010%22"));
+        assert!(url.contains("fill=%22url(%23g)%22"));
+        for removed in ["script", "alert", "onload", "evil.example"] {
+            assert!(!url.contains(removed), "{} should be removed", removed);
+        }
+
+        let svg = concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg">"#,
+            "<style>@import url(https://evil.example/a.css);",
+            "rect { fill: url(http://evil.example/p.svg#x) }</style>",
+            r##"<a href="#top"><set attributeName="href" to="javascript:alert(1)"/>"##,
+            r##"<animate attributeName="href" values="#a; java&#x09;script:alert(2)"/>"##,
+            r#"<set attributeName="href" to="https://evil.example/t"/>"#,
+            r##"<animate attributeName="xlink:href" values="#a;https://evil.example/v"/>"##,
+            r##"<set attributeName="href" to="#kept"/>"##,
+            r#"<animate attributeName="opacity" values="0;1" dur="1s"/></a></svg>"#,
+        );
+        let url = svg_to_data_url(svg).unwrap();
+        for removed in ["evil.example", "style", "javascript", "alert"] {
+            assert!(!url.contains(removed), "{} should be removed", removed);
+        }
+        assert!(url.contains("values=%220;1%22"));
+        assert!(url.contains("to=%22%23kept%22"));
+
+        let svg = concat!(
+            "<?xml version=\"1.0\"?>\n",
+            r#"<!DOCTYPE svg [<!ENTITY x SYSTEM "https://evil.example/x">]>"#,
+            r#"<svg xmlns:l="http://www.w3.org/1999/xlink"><?php echo 1 ?>"#,
+            r#"<!ENTITY y SYSTEM "file:///etc/passwd"><use l:href="https://evil.example/u.svg"/>"#,
+            r##"<use L:HREF="#ok"/>&x;</svg>"##,
+        );
+        let url = svg_to_data_url(svg).unwrap();
+        assert!(url.starts_with("data:image/svg+xml,%3Csvg"));
+        for removed in [
+            "evil.example",
+            "ENTITY",
+            "DOCTYPE",
+            "php",
+            "passwd",
+            "%5D%3E",
+        ] {
+            assert!(!url.contains(removed), "{} should be removed", removed);
+        }
+        assert!(url.contains("L:HREF=%22%23ok%22"));
+        assert!(svg_to_data_url("<html><body></body></html>").is_err());
+        assert!(svg_to_data_url("not markup").is_err());
+    }
+
+    #[test]
+    fn test_split_sections() {
+        let text = "Intro line\n### SECTION: plan\nStep one\nStep two\n\n\
+                    ### SECTION: code\nfn main() {}\n";
+        assert_eq!(
+            split_sections(text, "### SECTION:"),
+            vec![
+                (String::new(), "Intro line".to_string()),
+                ("plan".to_string(), "Step one\nStep two".to_string()),
+                ("code".to_string(), "fn main() {}".to_string()),
+            ]
+        );
+        assert_eq!(
+            split_sections("### SECTION: only\nbody", "### SECTION:").len(),
+            1
+        );
+        assert_eq!(
+            split_sections("no markers", "### SECTION:"),
+            vec![(String::new(), "no markers".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_url_display_form() {
+        assert_eq!(
+            url_display_form(
+                "https://example.com/my%20notes/a%2Fb?q=caf%C3%A9%26more",
+                true
+            )
+            .unwrap(),
+            "https://example.com/my%20notes/a%2Fb?q=café%26more"
+        );
+        assert_eq!(
+            url_display_form("https://xn--mnchen-3ya.de:8080/", true).unwrap(),
+            "https://münchen.de:8080/"
+        );
+        assert_eq!(
+            url_display_form("https://xn--mnchen-3ya.de:8080/", false).unwrap(),
+            "https://xn--mnchen-3ya.de:8080/"
+        );
+        assert_eq!(
+            url_display_form("https://example.com/%FF", true).unwrap(),
+            "https://example.com/%FF"
+        );
+        // Bidi overrides and zero-width characters could disguise the URL
+        assert_eq!(
+            url_display_form("https://example.com/%E2%80%AEfdp.exe%E2%80%8B%C3%A9", true).unwrap(),
+            "https://example.com/%E2%80%AEfdp.exe%E2%80%8Bé"
+        );
+        assert!(url_display_form("not a url", true).is_err());
+    }
+
+    #[test]
+    fn test_is_probably_text() {
+        assert!(is_probably_text("Grüße, мир!\n\tindented\r\n".as_bytes()));
+        assert!(is_probably_text(b""));
+        assert!(!is_probably_text(b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR"));
+        assert!(!is_probably_text(&[0x01, 0x02, 0x03, b'a', 0x04, 0x05]));
+    }
+
+    #[test]
+    fn test_shift_heading_levels() {
+        assert_eq!(
+            shift_heading_levels(r#"<h1 id="top">Title</h1><p>x</p><H2>Sub</H2>"#, 1),
+            r#"<h2 id="top">Title</h2><p>x</p><H3>Sub</H3>"#
+        );
+        assert_eq!(shift_heading_levels("<h6>Deep</h6>", 2), "<h6>Deep</h6>");
+        assert_eq!(
+            shift_heading_levels("<h2>A</h2><h1>B</h1>", -1),
+            "<h1>A</h1><h1>B</h1>"
+        );
+        assert_eq!(shift_heading_levels("<hr><h7>x</h7>", 1), "<hr><h7>x</h7>");
+    }
+
+    #[test]
+    fn test_extract_main_content() {
+        let article = "<div class=\"post\"><h1>Rust ownership</h1>\
+            <p>Every value in Rust has a single owner, and the value is dropped when its \
+            owner goes out of scope.</p><p>Borrowing lets code use a value without taking \
+            ownership, and the borrow checker makes sure references never outlive it.</p></div>";
+        let html = format!(
+            "<html><body><nav><a href=\"/\">Home</a> <a href=\"/blog\">Blog</a></nav>\
+             <div id=\"wrapper\"><div class=\"sidebar\"><ul><li><a href=\"/a\">Archive one</a></li>\
+             <li><a href=\"/b\">Archive two</a></li><li><a href=\"/c\">Archive three</a></li></ul>\
+             </div>{}</div><footer>Copyright 2024 Example Inc. All rights reserved.</footer>\
+             </body></html>",
+            article
+        );
+        assert_eq!(extract_main_content(&html).as_deref(), Some(article));
+        assert_eq!(
+            extract_main_content("<div><a href=\"/\">Home</a></div><div>Short.</div>"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_multi_keyword_spans() {
+        let keywords = vec!["learning".to_string(), "Machine Learning".to_string()];
+        assert_eq!(
+            multi_keyword_spans("machine learning beats LEARNING", keywords),
+            vec![
+                (0, 16, "Machine Learning".to_string()),
+                (23, 31, "learning".to_string()),
+            ]
+        );
+        let keywords = vec!["new york".to_string(), "york city".to_string()];
+        assert_eq!(
+            multi_keyword_spans("new york city", keywords),
+            vec![(4, 13, "york city".to_string())]
+        );
+        assert_eq!(
+            multi_keyword_spans("Ünïcode ünïcode", vec!["ÜNÏCODE".to_string()]),
+            vec![
+                (0, 9, "ÜNÏCODE".to_string()),
+                (10, 19, "ÜNÏCODE".to_string())
+            ]
+        );
+        let keywords = vec!["ab".to_string(), "xaby".to_string()];
+        assert_eq!(
+            multi_keyword_spans("xaby ab", keywords),
+            vec![(0, 4, "xaby".to_string()), (5, 7, "ab".to_string())]
+        );
+        // Every offset is a candidate; alternating ones are kept
+        let spans = multi_keyword_spans(&"a".repeat(100_000), vec!["aa".to_string()]);
+        assert_eq!(spans.len(), 50_000);
+        assert!(spans.windows(2).all(|w| w[0].1 == w[1].0));
+    }
+
+    #[test]
+    fn test_line_diff_ignore_whitespace() {
+        let old = "fn main() {\nprintln!(\"hi\");\n}";
+        let new = "fn main() {\n    println!(\"hi\");\n}\n";
+        assert!(line_diff_ignore_whitespace(old, new)
+            .iter()
+            .all(|(op, _)| op == "equal"));
+
+        let diff = line_diff_ignore_whitespace("a  b\nold line", "a b\nnew line");
+        assert_eq!(
+            diff,
+            vec![
+                ("equal".to_string(), "a b".to_string()),
+                ("removed".to_string(), "old line".to_string()),
+                ("added".to_string(), "new line".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extract_canonical_url() {
+        let html = r#"<head><link href="../posts/42" REL="Canonical"></head>"#;
+        assert_eq!(
+            extract_canonical_url(html, "https://example.com/blog/amp/42?utm=x").as_deref(),
+            Some("https://example.com/blog/posts/42")
+        );
+        assert_eq!(
+            extract_canonical_url(r#"<link rel="icon" href="/a.ico">"#, "https://example.com/"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_classify_links() {
+        let html = r#"<a href="/about">About</a>
+            <a href="https://docs.example.co.uk/guide">Docs</a>
+            <a href="https://other.co.uk/">Other</a>
+            <a href="mailto:me@example.co.uk">Mail</a>"#;
+        let (internal, external) = classify_links(html, "https://www.example.co.uk/").unwrap();
+        assert_eq!(
+            internal,
+            vec![
+                "https://www.example.co.uk/about",
+                "https://docs.example.co.uk/guide"
+            ]
+        );
+        assert_eq!(external, vec!["https://other.co.uk/"]);
+        assert!(classify_links(html, "not a url").is_err());
+    }
+
+    #[test]
+    fn test_unwrap_paragraphs() {
+        let text = "This sentence was wrapped\nat a narrow width\nby the model.\n\n\
+                    Second paragraph.";
+        assert_eq!(
+            unwrap_paragraphs(text),
+            "This sentence was wrapped at a narrow width by the model.\n\nSecond paragraph."
+        );
+
+        let text = "Steps:\n- first item\n- second item that\n  wraps\n1. numbered";
+        assert_eq!(
+            unwrap_paragraphs(text),
+            "Steps:\n- first item\n- second item that wraps\n1. numbered"
+        );
+
+        let text = "Code:\n```\nlet a = 1;\nlet b = 2;\n```\n# Title\nbody";
+        assert_eq!(unwrap_paragraphs(text), text);
+    }
+
+    #[test]
+    fn test_unwrap_paragraphs_keeps_quotes_and_indented_code() {
+        assert_eq!(unwrap_paragraphs("c\n> q1\n> q2"), "c\n> q1\n> q2");
+        assert_eq!(
+            unwrap_paragraphs("text\n  indented code"),
+            "text\n  indented code"
+        );
+        assert_eq!(
+            unwrap_paragraphs("Run:\n\n    cargo build\n    cargo test\nthen\nstop"),
+            "Run:\n\n    cargo build\n    cargo test\nthen stop"
+        );
+    }
+
+    #[test]
+    fn test_extract_open_graph() {
+        let html = r#"<head>
+            <meta property="og:title" content="Tom &amp; Jerry">
+            <meta name="description" content="ignored">
+            <meta content="https://example.com/1.png" property="og:image">
+            <meta property="OG:IMAGE" content="https://example.com/2.png">
+        </head>"#;
+        assert_eq!(
+            extract_open_graph(html),
+            vec![
+                ("og:title".to_string(), "Tom & Jerry".to_string()),
+                (
+                    "og:image".to_string(),
+                    "https://example.com/1.png".to_string()
+                ),
+                (
+                    "og:image".to_string(),
+                    "https://example.com/2.png".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_short_hash() {
+        assert_eq!(short_hash("Introduction", 8), short_hash("Introduction", 8));
+        assert_eq!(short_hash("Introduction", 8).len(), 8);
+        assert!(short_hash("x", 8)
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric()));
+        assert!(short_hash("Introduction", 12).starts_with(&short_hash("Introduction", 8)));
+        assert_eq!(short_hash("x", 100).len(), 22);
+
+        let hashes: HashSet<String> = (0..10_000)
+            .map(|i| short_hash(&format!("heading {}", i), 8))
+            .collect();
+        assert_eq!(hashes.len(), 10_000);
+    }
+
+    #[test]
+    fn test_normalize_class_list() {
+        assert_eq!(
+            normalize_class_list("btn primary btn large primary"),
+            "btn primary large"
+        );
+        assert_eq!(normalize_class_list("  card\n\tshadow   "), "card shadow");
+        assert_eq!(normalize_class_list("A a"), "A a");
+        assert_eq!(normalize_class_list("   "), "");
+    }
+
+    #[test]
+    fn test_viewport_meta() {
+        let html = r#"<head><meta charset="utf-8">
+            <meta content="width=device-width, initial-scale=1" name="Viewport"></head>"#;
+        assert!(has_viewport_meta(html));
+        assert_eq!(
+            extract_viewport(html).as_deref(),
+            Some("width=device-width, initial-scale=1")
+        );
+
+        let html = r#"<head><meta name="description" content="x"></head>"#;
+        assert!(!has_viewport_meta(html));
+        assert_eq!(extract_viewport(html), None);
+    }
+
+    #[test]
+    fn test_split_by_byte_limit() {
+        assert_eq!(
+            split_by_byte_limit("alpha beta gamma", 11).unwrap(),
+            vec!["alpha beta", "gamma"]
+        );
+        // "é" is two bytes, so "café" is five and the limit falls mid-word
+        let chunks = split_by_byte_limit("café café café", 11).unwrap();
+        assert_eq!(chunks, vec!["café café", "café"]);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 11));
+
+        assert_eq!(
+            split_by_byte_limit("日本語テキスト", 7).unwrap(),
+            vec!["日本", "語テ", "キス", "ト"]
+        );
+        assert_eq!(split_by_byte_limit("", 5).unwrap(), Vec::<String>::new());
+        assert!(split_by_byte_limit("日本", 2).is_err());
+    }
+
+    #[test]
+    fn test_render_status_html() {
+        assert_eq!(
+            render_status_html("Fetching <page> & parsing", Some(150), false),
+            concat!(
+                r#"<div class="status" role="status">"#,
+                r#"<span class="status-label">Fetching &lt;page&gt; &amp; parsing</span>"#,
+                r#"<progress class="status-progress" max="100" value="100">100%</progress>"#,
+                "</div>"
+            )
+        );
+        let html = render_status_html("Thinking", None, true);
+        assert!(html.contains(r#"<span class="status-spinner" aria-hidden="true"></span>"#));
+        assert!(!html.contains("<progress"));
+        assert!(render_status_html("x", Some(0), false).contains(r#"value="0">0%"#));
+        assert!(render_status_html("x", Some(u8::MAX), false).contains(r#"value="100">100%"#));
+        assert!(render_status_html("x", Some(42), false).contains(r#"value="42">42%"#));
+    }
+
+    #[test]
+    fn test_markdown_to_html_autolink_emails() {
+        assert_eq!(
+            markdown_to_html("Write to support@example.com today.", true),
+            "Write to <a href=\"mailto:support@example.com\">support@example.com</a> today."
+        );
+        assert_eq!(
+            markdown_to_html("thanks @handle and a@b", true),
+            "thanks @handle and a@b"
+        );
+        assert_eq!(
+            markdown_to_html("Write to support@example.com", false),
+            "Write to support@example.com"
+        );
+        let linked = r#"<a href="mailto:me@example.com">me@example.com</a>"#;
+        assert_eq!(markdown_to_html(linked, true), linked);
+        assert_eq!(
+            markdown_to_html("see https://user@host.example.com/", true),
+            "see https://user@host.example.com/"
+        );
+    }
+
+    #[test]
+    fn test_sort_key() {
+        let entries = [
+            (200, "assistant", "b"),
+            (-5, "user", "a"),
+            (200, "user", "c"),
+            (1_000, "system", "d"),
+            (200, "user", "a"),
+            (200, "critic", "e"),
+        ];
+        let mut keys: Vec<(String, &str)> = entries
+            .iter()
+            .map(|&(timestamp, role, id)| (sort_key(timestamp, role, id), id))
+            .collect();
+        keys.sort();
+        let order: Vec<&str> = keys.iter().map(|(_, id)| *id).collect();
+        assert_eq!(order, vec!["a", "a", "c", "b", "e", "d"]);
+        assert_eq!(sort_key(200, "user", "c"), sort_key(200, "User", "c"));
+    }
+
+    #[test]
+    fn test_audit_image_alts() {
+        let html = r#"<img src="/logo.png" alt="Acme &amp; Co">
+            <img alt="" src="/divider.png"><IMG SRC="/chart.png"/>"#;
+        assert_eq!(
+            audit_image_alts(html),
+            vec![
+                ("/logo.png".to_string(), Some("Acme & Co".to_string())),
+                ("/divider.png".to_string(), Some(String::new())),
+                ("/chart.png".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_text_stats() {
+        assert_eq!(text_stats("héllo world\n\nlast line"), (22, 4, 3, 23));
+        assert_eq!(text_stats("one\ntwo\n"), (8, 2, 2, 8));
+        assert_eq!(text_stats(""), (0, 0, 0, 0));
+    }
+
+    #[test]
+    fn test_parse_fence_info() {
+        assert_eq!(parse_fence_info("rust"), ("rust".to_string(), vec![]));
+        assert_eq!(
+            parse_fence_info(r#"python title="app main.py" hl=1-3 linenos"#),
+            (
+                "python".to_string(),
+                vec![
+                    ("title".to_string(), "app main.py".to_string()),
+                    ("hl".to_string(), "1-3".to_string()),
+                ]
+            )
+        );
+        assert_eq!(
+            parse_fence_info("title='x'"),
+            (String::new(), vec![("title".to_string(), "x".to_string())])
+        );
+        assert_eq!(parse_fence_info(""), (String::new(), vec![]));
+    }
+
+    #[test]
+    fn test_identity_color() {
+        assert_eq!(identity_color("assistant"), identity_color("assistant"));
+        assert_eq!(identity_color("Alice"), identity_color(" alice"));
+        let color = identity_color("planner");
+        assert!(color.len() == 7 && color.starts_with('#'));
+
+        let names = [
+            "alice", "bob", "carol", "dave", "erin", "frank", "grace", "heidi",
+        ];
+        let colors: HashSet<String> = names.iter().map(|name| identity_color(name)).collect();
+        assert!(colors.len() > 4);
+    }
+
+    #[test]
+    fn test_json_has_keys() {
+        let message = r#"{"type": "tool_call", "id": 7, "args": {"url": "x"}}"#;
+        let keys = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        assert!(json_has_keys(message, keys(&["type", "id"])).unwrap());
+        assert!(!json_has_keys(message, keys(&["type", "url"])).unwrap());
+        assert!(json_has_keys(message, vec![]).unwrap());
+        assert!(json_has_keys("[1, 2]", keys(&["type"])).is_err());
+        assert!(json_has_keys("{\"type\":", keys(&["type"])).is_err());
+    }
+
+    #[test]
+    fn test_extract_code_and_output() {
+        let text = "Run this:\n```python\nprint(1 + 1)\n```\n\n```output\n2\n```\nDone.";
+        assert_eq!(
+            extract_code_and_output(text),
+            Some(("print(1 + 1)".to_string(), "2".to_string()))
+        );
+        assert_eq!(extract_code_and_output("```rust\nfn main() {}\n```"), None);
+        let separated = "```sh\nls\n```\nThe listing:\n```text\na.txt\n```";
+        assert_eq!(extract_code_and_output(separated), None);
+        let other = "```sh\nls\n```\n```python\nx = 1\n```";
+        assert_eq!(extract_code_and_output(other), None);
+    }
+
+    #[test]
+    fn test_split_complete_utf8() {
+        let data = "price: 5€".as_bytes();
+        let (text, tail) = split_complete_utf8(&data[..data.len() - 1]);
+        assert_eq!(text, "price: 5");
+        assert_eq!(tail, vec![0xE2, 0x82]);
+
+        let mut next = tail;
+        next.extend_from_slice(&data[data.len() - 1..]);
+        assert_eq!(split_complete_utf8(&next), ("€".to_string(), vec![]));
+
+        assert_eq!(
+            split_complete_utf8("done 😀".as_bytes()),
+            ("done 😀".to_string(), vec![])
+        );
+        assert_eq!(
+            split_complete_utf8(b"a\xFFb"),
+            ("a\u{FFFD}b".to_string(), vec![])
+        );
+        assert_eq!(split_complete_utf8(b""), (String::new(), vec![]));
+    }
+
+    #[test]
+    fn test_longest_repeated_substring() {
+        let text = "Home | About | Contact. Article one. Home | About | Contact. Article two.";
+        assert_eq!(
+            longest_repeated_substring(text, 5).as_deref(),
+            Some("Home | About | Contact. Article ")
+        );
+        assert_eq!(
+            longest_repeated_substring("banana", 1).as_deref(),
+            Some("ana")
+        );
+        assert_eq!(longest_repeated_substring("banana", 4), None);
+        assert_eq!(longest_repeated_substring("héhé", 2).as_deref(), Some("hé"));
+        assert_eq!(longest_repeated_substring("abc", 1), None);
+    }
+
+    #[test]
+    fn test_markdown_to_html_definition_list() {
+        let text = "Glossary\n\n**Rust**\n: A systems language\n\
+                    Cargo\n: The *build* tool\n: Also a registry client\nThe end.";
+        assert_eq!(
+            markdown_to_html(text, false),
+            "Glossary\n\n<dl>\n<dt><strong>Rust</strong></dt>\n<dd>A systems language</dd>\n\
+             <dt>Cargo</dt>\n<dd>The <em>build</em> tool</dd>\n<dd>Also a registry client</dd>\n\
+             </dl>\nThe end."
+        );
+        assert_eq!(
+            markdown_to_html(": not a definition", false),
+            ": not a definition"
+        );
+        assert_eq!(markdown_to_html("---\n: text", false), "<hr>\n: text");
+        assert_eq!(
+            markdown_to_html("- [ ] item\n: text", false),
+            "<ul class=\"task-list\">\n<li><input type=\"checkbox\" disabled>item</li>\n\
+             </ul>\n: text"
+        );
+    }
+
+    #[test]
+    fn test_extract_phone_numbers() {
+        let text = "Call (415) 555-2671 or +1 415-555-2672. Order #12345678 \
+                    ships 2024-01-15; again: 415.555.2671";
+        assert_eq!(
+            extract_phone_numbers(text, "US"),
+            vec!["+14155552671", "+14155552672"]
+        );
+        assert_eq!(
+            extract_phone_numbers("Ring +44 20 7946 0958", "us"),
+            vec!["+442079460958"]
+        );
+        assert!(extract_phone_numbers("build 1234567", "US").is_empty());
+    }
+
+    #[test]
+    fn test_keyword_diff() {
+        let keywords: HashSet<String> = ["Rust", "python", "cargo", "pip", "go"]
+            .iter()
+            .map(|k| k.to_string())
+            .collect();
+        let (only_a, only_b, both) = keyword_diff(
+            "Build the Rust crate with Cargo, then go.",
+            "Install with pip and let rust and Python go.",
+            keywords,
+        );
+        assert_eq!(only_a, vec!["cargo"]);
+        assert_eq!(only_b, vec!["pip", "python"]);
+        assert_eq!(both, vec!["Rust", "go"]);
+    }
+
+    #[test]
+    fn test_sanitize_inline_style() {
+        let allowed: HashSet<String> = ["color", "background", "font-family"]
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+        assert_eq!(
+            sanitize_inline_style(
+                "COLOR: red; background: url(https://evil.example/x.png); position: fixed",
+                allowed.clone()
+            ),
+            "color: red"
+        );
+        assert_eq!(
+            sanitize_inline_style(
+                r#"font-family: 'a;b', serif; background: u\72l(x); color: expression (alert(1))"#,
+                allowed.clone()
+            ),
+            "font-family: 'a;b', serif"
+        );
+        assert_eq!(
+            sanitize_inline_style(
+                r#"color: red" onmouseover="alert(1)"; font-family: "x", serif"#,
+                allowed.clone()
+            ),
+            ""
+        );
+        assert_eq!(
+            sanitize_inline_style("color: red</style><script>; color: blue", allowed.clone()),
+            "color: blue"
+        );
+        assert_eq!(
+            sanitize_inline_style(
+                "color: red&#x3b background&#x3a u&#x72l(https://evil.example/x); \
+                 background: u&#114;l(x); color: blue",
+                allowed.clone()
+            ),
+            "color: blue"
+        );
+        assert_eq!(
+            sanitize_inline_style(
+                "background: image-set('x.png' 1x); background: IMAGE ('y.png'); color: red",
+                allowed
+            ),
+            "color: red"
+        );
+    }
+
+    #[test]
+    fn test_canonicalize_html() {
+        let a = "<DIV Class=\"note\" id='x'>\n  Hello   <BR/>world</DIV>";
+        let b = "<div id=\"x\" class=note> Hello <br>world</div >";
+        assert_eq!(canonicalize_html(a), canonicalize_html(b));
+        assert_eq!(
+            canonicalize_html(b),
+            "<div class=\"note\" id=\"x\"> Hello <br>world</div>"
+        );
+        assert_eq!(
+            canonicalize_html("<pre Data-X=1>a  b</pre><input disabled>"),
+            "<pre data-x=\"1\">a  b</pre><input disabled=\"\">"
+        );
+        assert_ne!(canonicalize_html("<p>a</p>"), canonicalize_html("<p>b</p>"));
+    }
+
+    #[test]
+    fn test_extract_quotes() {
+        let text = r#"He said "it's 'fine' today" and wrote “no comment”, then "left"#;
+        assert_eq!(
+            extract_quotes(text),
+            vec!["it's 'fine' today", "no comment"]
+        );
+        assert_eq!(extract_quotes("“open only"), Vec::<String>::new());
+        assert_eq!(extract_quotes(r#"empty "" quotes"#), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_plaintext_to_html_offset() {
+        let html = "<p>Hello <b>bold</b> world</p><!-- note -->";
+        // Plain text: "Hello bold world"
+        assert_eq!(plaintext_to_html_offset(html, 0), Some(3));
+        assert_eq!(plaintext_to_html_offset(html, 6), Some(12));
+        assert_eq!(
+            &html[plaintext_to_html_offset(html, 11).unwrap()..][..5],
+            "world"
+        );
+        assert_eq!(plaintext_to_html_offset(html, 16), Some(26));
+        assert_eq!(plaintext_to_html_offset(html, 17), None);
+        assert_eq!(plaintext_to_html_offset("<br>", 0), Some(0));
+
+        let html = "<style>p { x: 1 }</style>ab<script>var c;</script>cd";
+        assert_eq!(plaintext_to_html_offset(html, 0), Some(25));
+        assert_eq!(plaintext_to_html_offset(html, 2), Some(50));
+        assert_eq!(plaintext_to_html_offset(html, 4), Some(52));
+        assert_eq!(plaintext_to_html_offset(html, 5), None);
+    }
+
+    #[test]
+    fn test_plaintext_to_html_offset_decodes_entities() {
+        let html = "<p>a &amp; b &eacute;t&#233; &bogus; c</p>";
+        // Plain text: "a & b été &bogus; c"
+        assert_eq!(plaintext_to_html_offset(html, 2), Some(5));
+        assert_eq!(plaintext_to_html_offset(html, 4), Some(11));
+        assert_eq!(plaintext_to_html_offset(html, 6), Some(13));
+        assert_eq!(plaintext_to_html_offset(html, 7), Some(13));
+        assert_eq!(plaintext_to_html_offset(html, 8), Some(21));
+        assert_eq!(plaintext_to_html_offset(html, 12), Some(29));
+        assert_eq!(
+            &html[plaintext_to_html_offset(html, 20).unwrap()..][..1],
+            "c"
+        );
+        assert_eq!(plaintext_to_html_offset(html, 21), Some(38));
+        assert_eq!(plaintext_to_html_offset(html, 22), None);
+    }
+
+    #[test]
+    fn test_markdown_to_html_sup_sub_strike() {
+        assert_eq!(markdown_to_html("E=mc^2^", false), "E=mc<sup>2</sup>");
+        assert_eq!(markdown_to_html("H~2~O", false), "H<sub>2</sub>O");
+        assert_eq!(
+            markdown_to_html("~~strike~~ it", false),
+            "<del>strike</del> it"
+        );
+        assert_eq!(
+            markdown_to_html("~~old~~ H~2~O ^a^", false),
+            "<del>old</del> H<sub>2</sub>O <sup>a</sup>"
+        );
+        assert_eq!(
+            markdown_to_html("about ~5 km or ~6 km", false),
+            "about ~5 km or ~6 km"
+        );
+        assert!(!markdown_to_html("$x^2^$", false).contains("<sup>"));
+        assert_eq!(
+            markdown_to_html(&markdown_escape("x^2^ and H~2~O"), false),
+            "x&#94;2&#94; and H&#126;2&#126;O"
+        );
+    }
+
+    #[test]
+    fn test_markdown_to_html_sub_sup_leave_urls_and_paths() {
+        assert_eq!(
+            markdown_to_html("see http://a.com/~x/~y now", false),
+            "see http://a.com/~x/~y now"
+        );
+        assert_eq!(
+            markdown_to_html("home is http://a.com/~user~ ok", false),
+            "home is http://a.com/~user~ ok"
+        );
+        assert_eq!(markdown_to_html("cd ~/path/~", false), "cd ~/path/~");
+        assert_eq!(markdown_to_html("a/^b^", false), "a/^b^");
+    }
+
+    #[test]
+    fn test_extract_css_variables() {
+        let css = "body { --not-root: 1px; }\n\
+                   :root, [data-theme=\"light\"] {\n\
+                     --color-bg: #ffffff; /* page background */\n\
+                     --color-fg: rgb(20, 20, 20);\n\
+                     /* --color-old: red; */\n\
+                     --font-stack: \"Inter\", sans-serif\n\
+                   }\n\
+                   @media (prefers-color-scheme: dark) { :root { --color-bg: #111; } }";
+        assert_eq!(
+            extract_css_variables(css),
+            vec![
+                ("--color-bg".to_string(), "#ffffff".to_string()),
+                ("--color-fg".to_string(), "rgb(20, 20, 20)".to_string()),
+                (
+                    "--font-stack".to_string(),
+                    "\"Inter\", sans-serif".to_string()
+                ),
+                ("--color-bg".to_string(), "#111".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extract_css_variables_semicolons_in_values() {
+        let css = ":root { --a: 1; --b: url(\"x;y\"); --c: 'p;q'; --d: url(a;b) }";
+        assert_eq!(
+            extract_css_variables(css),
+            vec![
+                ("--a".to_string(), "1".to_string()),
+                ("--b".to_string(), "url(\"x;y\")".to_string()),
+                ("--c".to_string(), "'p;q'".to_string()),
+                ("--d".to_string(), "url(a;b)".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_collect_used_classes() {
+        let html = r#"<div class="card  shadow"><p class="card-body text-muted">x</p>
+            <span CLASS="text-muted">y</span><br class=""></div>"#;
+        let expected: HashSet<String> = ["card", "shadow", "card-body", "text-muted"]
+            .iter()
+            .map(|class| class.to_string())
+            .collect();
+        assert_eq!(collect_used_classes(html), expected);
+        assert!(collect_used_classes("<p>plain</p>").is_empty());
+    }
+
+    #[test]
+    fn test_split_thinking() {
+        assert_eq!(
+            split_thinking("<think>\nThe user wants a sum.\n</think>\n\n2 + 2 = 4"),
+            (
+                Some("The user wants a sum.".to_string()),
+                "2 + 2 = 4".to_string()
+            )
+        );
+        assert_eq!(
+            split_thinking("  <THINKING>plan</Thinking>Answer"),
+            (Some("plan".to_string()), "Answer".to_string())
+        );
+        assert_eq!(
+            split_thinking("<think>still reasoning"),
+            (Some("still reasoning".to_string()), String::new())
+        );
+        assert_eq!(
+            split_thinking("Plain answer with <think> later"),
+            (None, "Plain answer with <think> later".to_string())
+        );
+    }
+
+    #[test]
+    fn test_base64_decoded_len() {
+        for input in [
+            &b""[..],
+            b"f",
+            b"fo",
+            b"foo",
+            b"foob",
+            b"\x00\xff\x10hello world",
+        ] {
+            let encoded = general_purpose::STANDARD.encode(input);
+            assert_eq!(base64_decoded_len(&encoded).unwrap(), input.len());
+            let unpadded = encoded.trim_end_matches('=');
+            assert_eq!(base64_decoded_len(unpadded).unwrap(), input.len());
+        }
+        assert_eq!(base64_decoded_len("Zm9v\nYmFy").unwrap(), 6);
+        assert!(base64_decoded_len("Zm9vY").is_err());
+        assert!(base64_decoded_len("Zm8=").is_ok());
+        assert!(base64_decoded_len("Zm=v").is_err());
+        assert!(base64_decoded_len("Zg===").is_err());
+        assert!(base64_decoded_len("Z!==").is_err());
+
+        // Both functions reject non-zero trailing bits
+        for input in ["SGk=", "SGk", "SGl=", "SGl", "Zh==", "Zg"] {
+            assert_eq!(
+                base64_decoded_len(input).is_ok(),
+                canonicalize_base64(input).is_ok(),
+                "{}",
+                input
+            );
+        }
+        assert!(base64_decoded_len("SGl=").is_err());
     }
 }
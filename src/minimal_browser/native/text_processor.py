@@ -7,10 +7,75 @@ to pure Python implementations.
 
 from __future__ import annotations
 
+import html
 import re
 from typing import Optional, Tuple
 
 
+_EMAIL_CANDIDATE_RE = re.compile(
+    r"[A-Za-z0-9.!#$%&'*+/=?^_`{|}~-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)+"
+)
+# Tags and whole <a> elements, whose contents are never autolinked
+_TAG_OR_LINK_RE = re.compile(
+    r"(<a\b[^>]*>.*?</a\s*>|<[^>]*>)", re.IGNORECASE | re.DOTALL
+)
+_EMAIL_LOCAL_SPECIALS = "!#$%&'*+/=?^_`{|}~-"
+
+
+def _is_valid_email(email: str) -> bool:
+    """Python mirror of the native ``is_valid_email`` check."""
+    local, sep, domain = email.partition("@")
+    if not sep or not local or len(local) > 64 or len(domain) > 253:
+        return False
+
+    local_ok = all(
+        atom
+        and all(
+            (c.isascii() and c.isalnum()) or c in _EMAIL_LOCAL_SPECIALS for c in atom
+        )
+        for atom in local.split(".")
+    )
+
+    labels = domain.split(".")
+    domain_ok = (
+        len(labels) >= 2
+        and all(
+            label
+            and len(label) <= 63
+            and not label.startswith("-")
+            and not label.endswith("-")
+            and all((c.isascii() and c.isalnum()) or c == "-" for c in label)
+            for label in labels
+        )
+        and len(labels[-1]) >= 2
+        and not labels[-1].isdigit()
+    )
+
+    return local_ok and domain_ok
+
+
+def _autolink_email_addresses(text: str) -> str:
+    """Wrap bare email addresses in mailto: links, like the native renderer.
+
+    Text inside tags and existing <a> elements is left alone, as are
+    addresses that are part of a URL or fail ``_is_valid_email``.
+    """
+
+    def link(match: re.Match[str]) -> str:
+        email = match.group(0)
+        in_url = match.string[: match.start()].endswith((":", "/"))
+        if in_url or not _is_valid_email(email):
+            return email
+        return f'<a href="mailto:{html.escape(email)}">{email}</a>'
+
+    parts = _TAG_OR_LINK_RE.split(text)
+    # split() with a capturing group puts the tags at odd indices
+    return "".join(
+        part if index % 2 else _EMAIL_CANDIDATE_RE.sub(link, part)
+        for index, part in enumerate(parts)
+    )
+
+
 class TextProcessor:
     """Text processing with optional native acceleration."""
 
@@ -142,13 +207,19 @@ class TextProcessor:
         return base64.b64encode(data).decode("ascii")
 
     @staticmethod
-    def markdown_to_html(text: str) -> str:
+    def markdown_to_html(text: str, autolink_emails: bool = False) -> str:
         """Convert simple markdown formatting to HTML.
 
         Optimizes conversion of bold (**text**) and italic (*text*) markers.
 
+        The pure Python fallback is a reduced renderer: it only handles
+        bold, italic and email autolinking. Task lists, horizontal rules,
+        backslash escapes, definition lists, math and sup/sub/strikethrough
+        are only converted by the native module.
+
         Args:
             text: Markdown text
+            autolink_emails: Wrap bare email addresses in mailto: links
 
         Returns:
             HTML formatted text
@@ -156,7 +227,9 @@ class TextProcessor:
         # Use native module if available
         if TextProcessor._check_native_module() and TextProcessor._native_module:
             try:
-                return TextProcessor._native_module.markdown_to_html(text)
+                return TextProcessor._native_module.markdown_to_html(
+                    text, autolink_emails=autolink_emails
+                )
             except Exception:
                 # Fall back to Python on any error
                 pass
@@ -164,4 +237,6 @@ class TextProcessor:
         # Pure Python fallback
         result = re.sub(r"\*\*(.*?)\*\*", r"<strong>\1</strong>", text)
         result = re.sub(r"\*(.*?)\*", r"<em>\1</em>", result)
+        if autolink_emails:
+            result = _autolink_email_addresses(result)
         return result